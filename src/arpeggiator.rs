@@ -1,17 +1,24 @@
 use std::error::Error;
-use wmidi::{ControlFunction, MidiMessage, U7};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use wmidi::{Channel, ControlFunction, MidiMessage, U7};
 
 use strum_macros::EnumIter;
 
 use crate::arpeggio::{NoteDetails, Step};
 use crate::presets::Preset;
 use crate::status::StatusSignal;
-use crate::midi::{InputDevice, MidiReceiver, OutputDevice};
+use crate::midi::{MidiIn, MidiOut, MidiReceiver};
+use crate::midi::filter::Source;
 use crate::settings::{Settings, SettingsGetter};
+use crate::smf::MidiFile;
+use recordings::{Recording, RecordingStore};
 
 pub mod timed;
 pub mod synced;
 pub mod full_length;
+pub mod velocity;
+pub mod recordings;
 
 #[derive(Copy, Clone, EnumIter, Debug, Serialize, Deserialize, PartialEq)]
 pub enum Pattern {
@@ -68,10 +75,40 @@ impl Pattern {
     }
 }
 
+/// Selects which slot (numbered by the value) PedalRecorders record into and play back from
+const SLOT_CONTROL: ControlFunction = ControlFunction::GENERAL_PURPOSE_CONTROLLER_6;
+
+/// Sent with a value of 64 or more to undo the last overdub of a SyncedPedalRecorder
+const UNDO_CONTROL: ControlFunction = ControlFunction::GENERAL_PURPOSE_CONTROLLER_7;
+
+/// Sent with a value of 64 or more to export the recorded arpeggio of a PedalRecorder to a MIDI file
+const EXPORT_CONTROL: ControlFunction = ControlFunction::GENERAL_PURPOSE_CONTROLLER_5;
+
+/// Write a recorded arpeggio to a new file in the export_directory (or the current directory if not set).
+/// Failing to export is reported but does not stop the arpeggiator.
+fn export_midi_file(file: MidiFile, settings: &Settings) {
+    let directory = Path::new(settings.export_directory.as_deref().unwrap_or("."));
+    // in milliseconds, so exports in quick succession don't overwrite each other
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let path = directory.join(format!("arpeggio-{}.mid", millis));
+    match file.write(&path) {
+        Ok(()) => println!("Exported recorded arpeggio to '{}'", path.display()),
+        Err(e) => println!("Unable to export recorded arpeggio: {}", e)
+    }
+}
+
 pub trait Arpeggiator {
-    fn process(&mut self, message: MidiMessage<'static>, settings: &Settings, signal: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>>;
+    fn process(&mut self, message: MidiMessage<'static>, received_at: Instant, settings: &Settings, signal: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>>;
     fn stop_arpeggios(&mut self) -> Result<(), Box<dyn Error>>;
     fn count_arpeggios(&self) -> usize;
+
+    /// The arpeggio recorded since this was last called (if any) and its slot, so it can be kept when the settings change
+    fn take_recording(&mut self) -> Option<(usize, Recording)> {
+        None
+    }
+
+    /// Restore an arpeggio previously recorded into this slot with the same settings
+    fn restore_recording(&mut self, _slot: usize, _recording: Recording) {}
 }
 
 #[derive(PartialEq, EnumIter, Copy, Clone, Debug, Serialize, Deserialize)]
@@ -88,7 +125,7 @@ pub enum ArpeggiatorMode {
 }
 
 impl ArpeggiatorMode {
-    fn create<'a>(&self, midi_out: &'a OutputDevice, presets: &Option<Vec<Preset>>, output_device_is_input_device: bool) -> Box<dyn Arpeggiator + 'a> {
+    fn create<'a>(&self, midi_out: &'a dyn MidiOut, presets: &Option<Vec<Preset>>, output_device_is_input_device: bool) -> Box<dyn Arpeggiator + 'a> {
         match self {
             Self::Passthrough => Box::new(Passthrough::new(midi_out, output_device_is_input_device)),
             Self::MutatingHold => Box::new(synced::MutatingHold::new(midi_out)),
@@ -117,24 +154,52 @@ impl ArpeggiatorMode {
     }
 }
 
-pub struct MultiArpeggiator<'a, SG: SettingsGetter, SS: StatusSignal> {
-    pub midi_in: InputDevice,
-    pub midi_out: OutputDevice,
+fn create_arpeggiator<'a>(settings: &Settings, midi_out: &'a dyn MidiOut, output_device_is_input_device: bool) -> Box<dyn Arpeggiator + 'a> {
+    if let Some(layers) = &settings.velocity_layers {
+        Box::new(velocity::VelocityLayers::new(midi_out, settings, layers, output_device_is_input_device))
+    } else {
+        settings.mode.create(midi_out, &settings.presets, output_device_is_input_device)
+    }
+}
+
+fn all_notes_off(midi_out: &dyn MidiOut) -> Result<(), Box<dyn Error>> {
+    let sender = midi_out.sender();
+    for c in 0..16 {
+        sender.send((MidiMessage::ControlChange(Channel::from_index(c)?, ControlFunction::ALL_NOTES_OFF, U7::MIN), Source::Reset))?;
+    }
+    Ok(())
+}
+
+pub struct MultiArpeggiator<'a, SG: SettingsGetter, SS: StatusSignal, I: MidiIn, O: MidiOut> {
+    pub midi_in: I,
+    pub midi_out: O,
     pub output_device_is_input_device: bool,
-    pub settings: SG,
+    pub settings: &'a mut SG,
     pub status: &'a mut SS
 }
 
-impl<'a, SS: StatusSignal, SG: SettingsGetter> MultiArpeggiator<'a, SG, SS> {
+impl<'a, SS: StatusSignal, SG: SettingsGetter, I: MidiIn, O: MidiOut> MultiArpeggiator<'a, SG, SS, I, O> {
     pub fn listen(self) -> Result<(), Box<dyn Error>> {
         self.listen_with_midi_receivers(Vec::new())
     }
 
     pub fn listen_with_midi_receivers(mut self, mut extra_midi_receivers: Vec<&mut dyn MidiReceiver>) -> Result<(), Box<dyn Error>> {
         let mut existing_settings = self.settings.get().clone();
-        let mut arpeggiator: Box<dyn Arpeggiator> = existing_settings.mode.create(&self.midi_out, &self.settings.get().presets, self.output_device_is_input_device);
+        let mut recordings = RecordingStore::new();
+        let mut arpeggiator: Box<dyn Arpeggiator> = create_arpeggiator(&existing_settings, &self.midi_out, self.output_device_is_input_device);
+        for (slot, recording) in recordings.get(&existing_settings) {
+            arpeggiator.restore_recording(slot, recording);
+        }
         loop {
-            let mut m = Some(self.midi_in.read()?);
+            let (mut m, received_at) = match self.midi_in.read() {
+                Ok((message, received_at)) => (Some(message), received_at),
+                Err(e) => {
+                    // a device has disconnected, so make sure nothing is left playing on the others
+                    arpeggiator.stop_arpeggios()?;
+                    all_notes_off(&self.midi_out)?;
+                    return Err(e);
+                }
+            };
             // pass message through extra receivers
             for midi_receiver in extra_midi_receivers.iter_mut() {
                 m = midi_receiver.passthrough_midi(m.unwrap());
@@ -149,7 +214,10 @@ impl<'a, SS: StatusSignal, SG: SettingsGetter> MultiArpeggiator<'a, SG, SS> {
             if new_settings != existing_settings {
                 existing_settings = new_settings;
                 arpeggiator.stop_arpeggios()?;
-                arpeggiator = existing_settings.mode.create(&self.midi_out, &self.settings.get().presets, self.output_device_is_input_device);
+                arpeggiator = create_arpeggiator(&existing_settings, &self.midi_out, self.output_device_is_input_device);
+                for (slot, recording) in recordings.get(&existing_settings) {
+                    arpeggiator.restore_recording(slot, recording);
+                }
                 self.status.update_count(arpeggiator.count_arpeggios());
             }
             // pass message through status
@@ -157,19 +225,22 @@ impl<'a, SS: StatusSignal, SG: SettingsGetter> MultiArpeggiator<'a, SG, SS> {
             m = self.status.passthrough_midi(m.unwrap());
             // process message in arp
             if m.is_none() { continue; }
-            arpeggiator.process(m.unwrap(), self.settings.get(), self.status)?;
+            arpeggiator.process(m.unwrap(), received_at, self.settings.get(), self.status)?;
+            if let Some((slot, recording)) = arpeggiator.take_recording() {
+                recordings.save(&existing_settings, slot, recording);
+            }
             self.status.update_count(arpeggiator.count_arpeggios());
         }
     }
 }
 
 struct Passthrough<'a> {
-    output: &'a OutputDevice,
+    output: &'a dyn MidiOut,
     output_device_is_input_device: bool
 }
 
 impl<'a> Passthrough<'a> {
-    fn new(output: &'a OutputDevice, output_device_is_input_device: bool) -> Self {
+    fn new(output: &'a dyn MidiOut, output_device_is_input_device: bool) -> Self {
         Self {
             output,
             output_device_is_input_device
@@ -212,7 +283,7 @@ impl<'a> Passthrough<'a> {
 }
 
 impl<'a> Arpeggiator for Passthrough<'a> {
-    fn process(&mut self, mut message: MidiMessage<'static>, settings: &Settings, _signal: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, mut message: MidiMessage<'static>, _received_at: Instant, settings: &Settings, _signal: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         if self.should_passthrough(&message) {
             if let Some(fixed) = settings.fixed_velocity {
                 message = match message {
@@ -222,11 +293,7 @@ impl<'a> Arpeggiator for Passthrough<'a> {
                     _ => message
                 };
             }
-            if let Some(doubling) = &settings.double_notes {
-                self.output.send_with_doubling(message, doubling.iter())?;
-            } else {
-                self.output.send(message)?;
-            }
+            self.output.passthrough_with_settings(settings).send(message)?;
         }
         Ok(())
     }
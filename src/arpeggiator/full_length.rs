@@ -1,9 +1,11 @@
 use wmidi::{Channel, MidiMessage, Note, Velocity, U7};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::mem;
 use std::sync::mpsc;
-use crate::midi;
+use std::time::Instant;
+use crate::chord::Chord;
+use crate::midi::{self, MidiOutput};
 use crate::arpeggio::full_length::{Arpeggio, Player};
 use crate::presets::Preset;
 use crate::settings::Settings;
@@ -11,7 +13,7 @@ use crate::status::StatusSignal;
 use super::Arpeggiator;
 
 pub struct EvenMutator<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     arpeggio: State
 }
 
@@ -22,7 +24,7 @@ enum State {
 }
 
 impl<'a> EvenMutator<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             arpeggio: State::None
@@ -34,7 +36,7 @@ const START_THRESHOLD_TICKS: u8 = 2;
 
 // NOTE: EvenMutator does not support sustain pedal (but could in the future)
 impl<'a> Arpeggiator for EvenMutator<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, _received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::NoteOn(_, n, actual_v) => {
                 let v = if let Some(fixed_v) = settings.fixed_velocity {
@@ -66,7 +68,7 @@ impl<'a> Arpeggiator for EvenMutator<'a> {
                         let mut temp = State::None;
                         mem::swap(&mut self.arpeggio, &mut temp);
                         if let State::Starting(arp, _) = temp {
-                            let mut player = Player::init(arp, self.midi_out, &settings.double_notes);
+                            let mut player = Player::init(arp, self.midi_out, settings);
                             self.arpeggio = if player.play_tick()? {
                                 State::Playing(player)
                             } else {
@@ -106,19 +108,19 @@ impl<'a> Arpeggiator for EvenMutator<'a> {
 }
 
 pub struct TriggeredChords<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     not_playing: HashSet<Preset>,
-    notes: HashSet<Note>,
-    playing: HashSet<Preset>,
+    notes: Vec<Note>, // in the order they were pressed
+    playing: HashMap<Preset, (MidiOutput, i8)>,
 }
 
 impl<'a> TriggeredChords<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice, presets: Vec<Preset>) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut, presets: Vec<Preset>) -> Self {
         Self {
             midi_out,
             not_playing: HashSet::from_iter(presets),
-            notes: HashSet::new(),
-            playing: HashSet::new()
+            notes: Vec::new(),
+            playing: HashMap::new()
         }
     }
 }
@@ -126,45 +128,62 @@ impl<'a> TriggeredChords<'a> {
 impl<'a> TriggeredChords<'a> {
     const SEND_CHANNEL: Channel = Channel::Ch1;
 
-    fn play(midi_out: &midi::OutputDevice, preset: &Preset, velocity: &Velocity) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
-        for n in preset.steps.iter() {
-            let message = MidiMessage::NoteOn(Self::SEND_CHANNEL, n.into(), *velocity);
+    fn play(midi_out: &MidiOutput, preset: &Preset, offset: i8, velocity: &Velocity) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
+        for n in Self::notes(preset, offset) {
+            let message = MidiMessage::NoteOn(Self::SEND_CHANNEL, n, *velocity);
             midi_out.send(message)?;
         }
         Ok(())
     }
 
-    fn stop(midi_out: &midi::OutputDevice, preset: &Preset) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
-        for n in preset.steps.iter() {
-            let message = MidiMessage::NoteOff(Self::SEND_CHANNEL, n.into(), U7::MIN);
+    fn stop(midi_out: &MidiOutput, preset: &Preset, offset: i8) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
+        for n in Self::notes(preset, offset) {
+            let message = MidiMessage::NoteOff(Self::SEND_CHANNEL, n, U7::MIN);
             midi_out.send(message)?;
         }
         Ok(())
     }
+
+    fn notes(preset: &Preset, offset: i8) -> Vec<Note> {
+        preset.step_notes().iter().filter_map(|n| {
+            let note: Note = n.into();
+            note.step(offset).ok()
+        }).collect()
+    }
 }
 
 impl<'a> Arpeggiator for TriggeredChords<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, _received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::NoteOn(_, n, mut v) => {
-                self.notes.insert(n);
-                let should_play: Vec<Preset> = self.not_playing.iter().filter(|p| p.is_triggered_by(&self.notes)).cloned().collect();
+                if !self.notes.contains(&n) {
+                    self.notes.push(n);
+                }
+                status.update_chord(Chord::recognise(&self.notes));
+                let should_play: Vec<(Preset, i8)> = self.not_playing.iter().filter_map(|p| p.trigger_offset(&self.notes).map(|o| (p.clone(), o))).collect();
                 if let Some(fixed_v) = settings.fixed_velocity {
                     v = U7::from_u8_lossy(fixed_v);
                 }
-                for p in should_play {
+                for (p, offset) in should_play {
                     self.not_playing.remove(&p);
-                    Self::play(self.midi_out, &p, &v)?;
-                    self.playing.insert(p);
+                    // keep the output used to play the preset, so it is stopped the same way
+                    let output = self.midi_out.with_settings(settings);
+                    Self::play(&output, &p, offset, &v)?;
+                    self.playing.insert(p, (output, offset));
                     status.reset_beat();
                 }
             },
             MidiMessage::NoteOff(_, n, _) => {
-                self.notes.remove(&n);
-                let should_stop: Vec<Preset> = self.playing.iter().filter(|p| !p.is_triggered_by(&self.notes)).cloned().collect();
+                self.notes.retain(|held| *held != n);
+                status.update_chord(Chord::recognise(&self.notes));
+                let should_stop: Vec<Preset> = self.playing.iter()
+                    .filter(|(p, (_, offset))| !p.is_triggered_at(&self.notes, *offset))
+                    .map(|(p, _)| p.clone())
+                    .collect();
                 for p in should_stop {
-                    self.playing.remove(&p);
-                    Self::stop(self.midi_out, &p)?;
+                    if let Some((output, offset)) = self.playing.remove(&p) {
+                        Self::stop(&output, &p, offset)?;
+                    }
                     self.not_playing.insert(p);
                 }
             },
@@ -174,8 +193,8 @@ impl<'a> Arpeggiator for TriggeredChords<'a> {
     }
 
     fn stop_arpeggios(&mut self) -> Result<(), Box<dyn Error>> {
-        for p in self.playing.drain() {
-            Self::stop(self.midi_out, &p)?;
+        for (p, (output, offset)) in self.playing.drain() {
+            Self::stop(&output, &p, offset)?;
             self.not_playing.insert(p);
         }
         Ok(())
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::arpeggio::{synced, timed};
+use crate::settings::Settings;
+use crate::smf::MidiFile;
+use super::ArpeggiatorMode;
+use super::velocity::VelocityLayers;
+
+#[derive(Clone)]
+pub enum Recording {
+    Synced(synced::Arpeggio),
+    Timed(timed::Arpeggio)
+}
+
+impl Recording {
+    fn to_midi_file(&self) -> MidiFile {
+        match self {
+            Self::Synced(arp) => arp.to_midi_file(synced::Arpeggio::DEFAULT_BPM),
+            Self::Timed(arp) => arp.to_midi_file()
+        }
+    }
+
+    fn from_midi_file(file: &MidiFile, settings: &Settings) -> Option<Self> {
+        match settings.mode {
+            ArpeggiatorMode::SyncedPedalRecorder => synced::Arpeggio::from_midi_file(file, settings.finish_pattern).map(Self::Synced),
+            ArpeggiatorMode::TimedPedalRecorder => timed::Arpeggio::from_midi_file(file, settings.finish_pattern).map(Self::Timed),
+            _ => None
+        }
+    }
+}
+
+/// The last arpeggio recorded into each slot with each set of settings, so they are kept when the settings change.
+/// If the settings have a recordings_directory, they are also saved there so they are kept after restarting.
+pub struct RecordingStore {
+    recordings: HashMap<String, HashMap<usize, Recording>>
+}
+
+impl RecordingStore {
+    pub fn new() -> Self {
+        Self {
+            recordings: HashMap::new()
+        }
+    }
+
+    /// The recordings for each slot with these settings, loading them from the recordings_directory the first time
+    pub fn get(&mut self, settings: &Settings) -> Vec<(usize, Recording)> {
+        let key = Self::key(settings);
+        if !self.recordings.contains_key(&key) {
+            let loaded = Self::load(settings, &key);
+            self.recordings.insert(key.clone(), loaded);
+        }
+        self.recordings[&key].iter().map(|(slot, r)| (*slot, r.clone())).collect()
+    }
+
+    pub fn save(&mut self, settings: &Settings, slot: usize, recording: Recording) {
+        let key = Self::key(settings);
+        if let Some(directory) = &settings.recordings_directory {
+            let path = Path::new(directory).join(Self::file_name(&key, slot));
+            if let Err(e) = recording.to_midi_file().write(&path) {
+                println!("Unable to save recorded arpeggio: {}", e);
+            }
+        }
+        self.recordings.entry(key).or_insert_with(HashMap::new).insert(slot, recording);
+    }
+
+    fn load(settings: &Settings, key: &str) -> HashMap<usize, Recording> {
+        let mut loaded = HashMap::new();
+        let directory = match &settings.recordings_directory {
+            Some(d) => Path::new(d),
+            None => return loaded
+        };
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return loaded // nothing has been saved yet
+        };
+        for entry in entries.flatten() {
+            let slot = match Self::slot_of(key, &entry.file_name().to_string_lossy()) {
+                Some(slot) => slot,
+                None => continue
+            };
+            let path = entry.path();
+            match MidiFile::read(&path) {
+                Ok(file) => match Recording::from_midi_file(&file, VelocityLayers::settings_for_slot(settings, slot)) {
+                    Some(recording) => {
+                        loaded.insert(slot, recording);
+                    },
+                    None => println!("No recorded arpeggio in '{}'", path.display())
+                },
+                Err(e) => println!("Unable to load recorded arpeggio: {}", e)
+            }
+        }
+        loaded
+    }
+
+    fn key(settings: &Settings) -> String {
+        serde_json::to_string(settings).unwrap_or_default()
+    }
+
+    /// Files are named by a hash of the settings, with the slot number added for slots other than 0
+    fn file_name(key: &str, slot: usize) -> String {
+        match slot {
+            0 => format!("{:016x}.mid", Self::hash(key)),
+            _ => format!("{:016x}-{}.mid", Self::hash(key), slot)
+        }
+    }
+
+    fn slot_of(key: &str, file_name: &str) -> Option<usize> {
+        let rest = file_name.strip_prefix(&format!("{:016x}", Self::hash(key)))?.strip_suffix(".mid")?;
+        match rest.strip_prefix('-') {
+            Some(slot) => slot.parse().ok().filter(|s| *s > 0),
+            None if rest.len() == 0 => Some(0),
+            None => None
+        }
+    }
+
+    /// A FNV-1a hash, which (unlike the std hasher) is the same every time the arpeggiator runs
+    fn hash(key: &str) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in key.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+}
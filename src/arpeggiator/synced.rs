@@ -3,16 +3,19 @@ use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::mem;
 use std::time::Instant;
+use crate::chord::Chord;
 use crate::midi;
 use crate::arpeggio::{NoteDetails, Step};
 use crate::arpeggio::synced::{Arpeggio, Player};
-use crate::presets::Preset;
-use crate::settings::Settings;
+use crate::presets::{self, Preset};
+use crate::midi::MidiReceiver;
+use crate::settings::{BpmDetector, Settings};
 use crate::status::StatusSignal;
 use super::Arpeggiator;
+use super::recordings::Recording;
 
 pub struct PressHold<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     held_notes: HashMap<Note, (Instant, NoteDetails)>,
     pedal_notes_off: HashSet<Note>,
     pedal: bool,
@@ -22,7 +25,7 @@ pub struct PressHold<'a> {
 impl<'a> PressHold<'a> {
     const TRIGGER_TIME_MS: u128 = 50;
 
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             held_notes: HashMap::new(),
@@ -43,7 +46,7 @@ impl<'a> PressHold<'a> {
 }
 
 impl<'a> Arpeggiator for PressHold<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::ControlChange(_, ControlFunction::DAMPER_PEDAL, value) => {
                 let new_pedal = u8::from(value) >= 64;
@@ -55,6 +58,7 @@ impl<'a> Arpeggiator for PressHold<'a> {
                         for n in notes_to_release {
                             self.release_note(n);
                         }
+                        status.update_chord(Chord::recognise(self.held_notes.keys()));
                     }
                 }
             },
@@ -63,7 +67,8 @@ impl<'a> Arpeggiator for PressHold<'a> {
                     // we are re-pressing a note which isn't actually off yet, because we're holding the pedal
                     // so we just removed it from what will be released when the pedal is released
                 } else {
-                    self.held_notes.insert(n, (Instant::now(), NoteDetails::new(c, n, v, settings.fixed_velocity)));
+                    self.held_notes.insert(n, (received_at, NoteDetails::new(c, n, v, settings.fixed_velocity)));
+                    status.update_chord(Chord::recognise(self.held_notes.keys()));
                 }
             },
             MidiMessage::NoteOff(_, n, _) => {
@@ -73,15 +78,16 @@ impl<'a> Arpeggiator for PressHold<'a> {
                     self.pedal_notes_off.insert(n);
                 } else {
                     self.release_note(n);
+                    status.update_chord(Chord::recognise(self.held_notes.keys()));
                 }
             },
             MidiMessage::TimingClock => {
-                if self.held_notes.len() != 0 && self.held_notes.values().map(|(i, _)| i).min().unwrap().elapsed().as_millis() > Self::TRIGGER_TIME_MS {
+                if self.held_notes.len() != 0 && received_at.duration_since(*self.held_notes.values().map(|(i, _)| i).min().unwrap()).as_millis() > Self::TRIGGER_TIME_MS {
                     let note_details: Vec<NoteDetails> = self.held_notes.drain().map(|(_, (_, d))| d).collect();
                     let note_set: HashSet<Note> = note_details.iter().map(|d| d.n).collect();
                     let steps = settings.generate_steps(note_details);
                     let arp = Arpeggio::from(steps, 1, settings.finish_pattern);
-                    self.arpeggios.push((note_set, Player::init(arp, &self.midi_out, &settings.double_notes, settings.max_repeats)));
+                    self.arpeggios.push((note_set, Player::init(arp, self.midi_out, settings)));
                     status.reset_beat();
                 }
                 let mut i = 0;
@@ -112,7 +118,7 @@ impl<'a> Arpeggiator for PressHold<'a> {
 }
 
 pub struct MutatingHold<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     held_notes: Vec<NoteDetails>,
     changed: bool,
     arpeggio: Option<Player>,
@@ -121,7 +127,7 @@ pub struct MutatingHold<'a> {
 }
 
 impl<'a> MutatingHold<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             held_notes: Vec::new(),
@@ -148,7 +154,7 @@ impl<'a> MutatingHold<'a> {
 }
 
 impl<'a> Arpeggiator for MutatingHold<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, _received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::ControlChange(_, ControlFunction::DAMPER_PEDAL, value) => {
                 let new_pedal = u8::from(value) >= 64;
@@ -195,7 +201,7 @@ impl<'a> Arpeggiator for MutatingHold<'a> {
                         if let Some(existing) = &mut self.arpeggio {
                             existing.change_arpeggio(arp)?;
                         } else {
-                            self.arpeggio = Some(Player::init(arp, &self.midi_out, &settings.double_notes, settings.max_repeats));
+                            self.arpeggio = Some(Player::init(arp, self.midi_out, settings));
                             status.reset_beat();
                         }
                     }
@@ -250,17 +256,24 @@ fn drain_and_force_stop_map<N>(arpeggios: &mut HashMap<N, Player>) -> Result<(),
 }
 
 pub struct PedalRecorder<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     notes: Vec<(Instant, NoteDetails)>,
     ticks_since_last_note: usize,
     thru_notes: HashMap<Note, NoteDetails>,
     pedal: bool,
     arpeggios: HashMap<Note, Player>,
-    recorded: Option<Arpeggio>
+    playing_slots: HashMap<Note, usize>, // the slot each playing arpeggio was started from
+    recorded: HashMap<usize, Arpeggio>, // by slot
+    slot: usize,
+    overdubbing: Option<(usize, Note)>, // the slot being overdubbed, and the note its arpeggio was started with
+    overdub_notes: Vec<(usize, NoteDetails)>, // with the index of the step they were played in
+    undo: HashMap<usize, Vec<Arpeggio>>, // the recorded arpeggio before each overdub, by slot
+    new_recording: Option<usize>, // the slot recorded into since the last take_recording
+    bpm: BpmDetector
 }
 
 impl<'a> PedalRecorder<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             notes: Vec::new(),
@@ -268,30 +281,76 @@ impl<'a> PedalRecorder<'a> {
             ticks_since_last_note: 0,
             pedal: false,
             arpeggios: HashMap::new(),
-            recorded: None
+            playing_slots: HashMap::new(),
+            recorded: HashMap::new(),
+            slot: 0,
+            overdubbing: None,
+            overdub_notes: Vec::new(),
+            undo: HashMap::new(),
+            new_recording: None,
+            bpm: BpmDetector::new()
         }
     }
 }
 
 impl<'a> PedalRecorder<'a> {
     const TRIGGER_TIME_MS: u128 = 50;
+
+    /// Start playing the arpeggio recorded in a slot, transposed to the given note
+    fn play(&mut self, slot: usize, n: Note, settings: &Settings) {
+        if let Some(arp) = self.recorded.get(&slot) {
+            let new_arp = arp.transpose(arp.original(), n, &settings.scale);
+            self.arpeggios.insert(n, Player::init(new_arp, self.midi_out, settings));
+            self.playing_slots.insert(n, slot);
+        }
+    }
+
+    /// Replace the recorded arpeggio in a slot, updating any arpeggios playing it without restarting them
+    fn replace_recorded(&mut self, slot: usize, arp: Arpeggio, settings: &Settings) -> Result<(), Box<dyn Error>> {
+        for (n, player) in self.arpeggios.iter_mut() {
+            if self.playing_slots.get(n) == Some(&slot) {
+                player.change_arpeggio(arp.transpose(arp.original(), *n, &settings.scale))?;
+            }
+        }
+        self.recorded.insert(slot, arp);
+        self.new_recording = Some(slot);
+        Ok(())
+    }
 }
 
 impl<'a> Arpeggiator for PedalRecorder<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::ControlChange(_, ControlFunction::DAMPER_PEDAL, value) => {
                 if !self.pedal && u8::from(value) >= 64 {
                     self.pedal = true;
-                    status.reset_beat();
-                    self.recorded = None;
-                    drain_and_force_stop_map(&mut self.arpeggios)?;
+                    // only arpeggios started from the selected slot are overdubbed, not ones still playing from another slot
+                    let playing = self.arpeggios.keys().filter(|n| self.playing_slots.get(n) == Some(&self.slot)).min().cloned();
+                    if playing.is_some() && self.recorded.contains_key(&self.slot) {
+                        // overdub into the playing arpeggio, rather than recording a new one
+                        self.overdubbing = playing.map(|n| (self.slot, n));
+                    } else {
+                        status.reset_beat();
+                        self.recorded.remove(&self.slot);
+                        self.undo.remove(&self.slot);
+                        drain_and_force_stop_map(&mut self.arpeggios)?;
+                    }
                 } else if self.pedal && u8::from(value) < 64 {
                     self.pedal = false;
                     for (_, thru_note) in self.thru_notes.drain() {
-                        self.midi_out.send(MidiMessage::NoteOff(thru_note.c, thru_note.n, thru_note.v))?;
+                        self.midi_out.passthrough_with_settings(settings).send(MidiMessage::NoteOff(thru_note.c, thru_note.n, thru_note.v))?;
                     }
-                    if self.notes.len() > 0 {
+                    if let Some((slot, played_from)) = self.overdubbing.take() {
+                        let notes = mem::replace(&mut self.overdub_notes, Vec::new());
+                        // the slot can have been changed while overdubbing, so use the one being overdubbed
+                        if let Some(previous) = self.recorded.get(&slot).cloned() {
+                            if !notes.is_empty() {
+                                let overdubbed = previous.overdub(&notes, played_from, &settings.scale);
+                                self.undo.entry(slot).or_default().push(previous);
+                                self.replace_recorded(slot, overdubbed, settings)?;
+                            }
+                        }
+                    } else if self.notes.len() > 0 {
                         // save recorded arpeggio
                         let notes = mem::replace(&mut self.notes, Vec::new());
                         let mut steps = Vec::new();
@@ -307,41 +366,68 @@ impl<'a> Arpeggiator for PedalRecorder<'a> {
                         }
                         steps.push(Step::notes(step_notes));
                         let total_beats = steps.len();
-                        self.recorded = Some(Arpeggio::from(steps, total_beats, settings.finish_pattern));
+                        let arp = Arpeggio::from(steps, total_beats, settings.finish_pattern);
+                        let original = arp.original();
+                        self.recorded.insert(self.slot, arp);
+                        self.new_recording = Some(self.slot);
                         // start play in original key
-                        let arp = self.recorded.as_ref().unwrap();
-                        let original = arp.first_note();
-                        let new_arp = arp.transpose(original, original);
-                        self.arpeggios.insert(original, Player::init(new_arp, &self.midi_out, &settings.double_notes, settings.max_repeats));
+                        self.play(self.slot, original, settings);
                         status.reset_beat();
                     }
                 }
             },
+            MidiMessage::ControlChange(_, super::SLOT_CONTROL, value) => {
+                // recorded arpeggios which are already playing continue, but new ones use the selected slot
+                self.slot = u8::from(value) as usize;
+            },
+            MidiMessage::ControlChange(_, super::UNDO_CONTROL, value) if u8::from(value) >= 64 => {
+                if self.overdubbing.is_none() {
+                    if let Some(previous) = self.undo.get_mut(&self.slot).and_then(|u| u.pop()) {
+                        self.replace_recorded(self.slot, previous, settings)?;
+                    }
+                }
+            },
+            MidiMessage::ControlChange(_, super::EXPORT_CONTROL, value) if u8::from(value) >= 64 => {
+                if let Some(arp) = self.recorded.get(&self.slot) {
+                    // use the default until a full beat of clock ticks has been received
+                    let bpm = match self.bpm.get() {
+                        0 => Arpeggio::DEFAULT_BPM,
+                        bpm => bpm
+                    };
+                    super::export_midi_file(arp.to_midi_file(bpm), settings);
+                }
+            },
             MidiMessage::NoteOn(c, n, v) => {
                 if self.pedal {
-                    self.midi_out.send(received)?;
+                    self.midi_out.passthrough_with_settings(settings).send(received)?;
                     let d = NoteDetails::new(c, n, v, settings.fixed_velocity);
                     self.thru_notes.insert(n, d);
-                    self.notes.push((Instant::now(), d));
+                    if let Some((_, played_from)) = self.overdubbing {
+                        // notes played after the overdubbed arpeggio has stopped are not recorded
+                        if let Some(player) = self.arpeggios.get(&played_from) {
+                            self.overdub_notes.push((player.nearest_step(), d));
+                        }
+                    } else {
+                        self.notes.push((received_at, d));
+                    }
                     self.ticks_since_last_note = 0;
                 } else if self.arpeggios.contains_key(&n) {
                     // already playing, do nothing
-                } else if let Some(arp) = &self.recorded {
-                    let original = arp.first_note();
-                    let new_arp = arp.transpose(original, n);
-                    self.arpeggios.insert(n, Player::init(new_arp, &self.midi_out, &settings.double_notes, settings.max_repeats));
+                } else if self.recorded.contains_key(&self.slot) {
+                    self.play(self.slot, n, settings);
                     status.reset_beat();
                 }
             },
             MidiMessage::NoteOff(_, n, _) => {
                 if self.pedal {
-                    self.midi_out.send(received)?;
+                    self.midi_out.passthrough_with_settings(settings).send(received)?;
                     self.thru_notes.remove(&n);
                 } else if let Some(player) = self.arpeggios.get_mut(&n) {
                     player.stop();
                 }
             },
             MidiMessage::TimingClock => {
+                self.bpm.passthrough_midi(received);
                 let mut finished = Vec::new();
                 for (note, player) in &mut self.arpeggios {
                     if !player.play_tick()? {
@@ -350,6 +436,7 @@ impl<'a> Arpeggiator for PedalRecorder<'a> {
                 }
                 for note in finished {
                     self.arpeggios.remove(&note);
+                    self.playing_slots.remove(&note);
                 }
                 self.ticks_since_last_note += 1;
             },
@@ -357,6 +444,8 @@ impl<'a> Arpeggiator for PedalRecorder<'a> {
                 self.notes.clear();
                 self.thru_notes.clear();
                 self.pedal = false;
+                self.overdubbing = None;
+                self.overdub_notes.clear();
                 drain_and_force_stop_map(&mut self.arpeggios)?;
             },
             _ => {}
@@ -371,22 +460,33 @@ impl<'a> Arpeggiator for PedalRecorder<'a> {
     fn count_arpeggios(&self) -> usize {
         self.arpeggios.len()
     }
+
+    fn take_recording(&mut self) -> Option<(usize, Recording)> {
+        let slot = self.new_recording.take()?;
+        self.recorded.get(&slot).map(|arp| (slot, Recording::Synced(arp.clone())))
+    }
+
+    fn restore_recording(&mut self, slot: usize, recording: Recording) {
+        if let Recording::Synced(arp) = recording {
+            self.recorded.insert(slot, arp);
+        }
+    }
 }
 
 pub struct PrerecordedSets<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     presets: Vec<Preset>,
-    notes: HashSet<Note>,
+    notes: Vec<Note>, // in the order they were pressed
     changed: bool,
     playing: Option<Player>,
 }
 
 impl<'a> PrerecordedSets<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice, presets: Vec<Preset>) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut, presets: Vec<Preset>) -> Self {
         Self {
             midi_out,
             presets,
-            notes: HashSet::new(),
+            notes: Vec::new(),
             changed: false,
             playing: None
         }
@@ -396,36 +496,32 @@ impl<'a> PrerecordedSets<'a> {
 impl<'a> PrerecordedSets<'a> {
     const SEND_CHANNEL: Channel = Channel::Ch1;
 
-    fn find_preset(&self, notes: &HashSet<Note>) -> Option<usize> {
-        for i in 0..self.presets.len() {
-            if self.presets[i].is_triggered_by(&notes) {
-                return Some(i);
-            }
-        }
-        None
-    }
 }
 
 impl<'a> Arpeggiator for PrerecordedSets<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, _received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::NoteOn(_, n, _) => {
-                self.notes.insert(n);
+                if !self.notes.contains(&n) {
+                    self.notes.push(n);
+                }
                 self.changed = true;
+                status.update_chord(Chord::recognise(&self.notes));
             },
             MidiMessage::NoteOff(_, n, _) => {
-                self.notes.remove(&n);
+                self.notes.retain(|held| *held != n);
                 self.changed = true;
+                status.update_chord(Chord::recognise(&self.notes));
             },
             MidiMessage::TimingClock => {
                 if self.changed {
                     self.changed = false;
-                    if let Some(p) = self.find_preset(&self.notes) {
+                    if let Some((p, offset)) = presets::find_preset(&self.presets, &self.notes) {
                         if let Some(existing) = &mut self.playing {
                             existing.force_stop()?;
                         }
-                        let new_arp = Arpeggio::from_preset(&self.presets[p], Self::SEND_CHANNEL, U7::from_u8_lossy(settings.fixed_velocity.unwrap_or(100)), settings.finish_pattern, settings.fixed_notes_per_step.unwrap_or(1));
-                        self.playing = Some(Player::init(new_arp, self.midi_out, &settings.double_notes, settings.max_repeats));
+                        let new_arp = Arpeggio::from_preset(&self.presets[p], offset, Self::SEND_CHANNEL, U7::from_u8_lossy(settings.fixed_velocity.unwrap_or(100)), settings.finish_pattern, settings.fixed_notes_per_step.unwrap_or(1));
+                        self.playing = Some(Player::init(new_arp, self.midi_out, settings));
                         status.reset_beat();
                     } else {
                         if let Some(existing) = &mut self.playing {
@@ -459,3 +555,62 @@ impl<'a> Arpeggiator for PrerecordedSets<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::mpsc, time::Duration};
+    use crate::arpeggiator::ArpeggiatorMode;
+    use crate::smf::MidiFile;
+    use crate::status::TextStatus;
+    use super::*;
+
+    fn pedal(down: bool) -> MidiMessage<'static> {
+        MidiMessage::ControlChange(Channel::Ch1, ControlFunction::DAMPER_PEDAL, U7::from_u8_lossy(if down { 127 } else { 0 }))
+    }
+
+    fn note_on(n: Note) -> MidiMessage<'static> {
+        MidiMessage::NoteOn(Channel::Ch1, n, U7::from_u8_lossy(100))
+    }
+
+    fn note_off(n: Note) -> MidiMessage<'static> {
+        MidiMessage::NoteOff(Channel::Ch1, n, U7::from_u8_lossy(0))
+    }
+
+    #[test]
+    fn overdubbed_recording_is_restored_with_its_original_note() {
+        let settings = Settings { mode: ArpeggiatorMode::SyncedPedalRecorder, ..Settings::passthrough() };
+        let mut status = TextStatus::_new(io::sink());
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        let mut recorder = PedalRecorder::new(&tx);
+        // record C4 then D4, which starts playing from C4
+        for (message, at) in [(pedal(true), 0), (note_on(Note::C4), 0), (note_off(Note::C4), 10), (note_on(Note::D4), 100), (note_off(Note::D4), 110), (pedal(false), 200)] {
+            recorder.process(message, start + Duration::from_millis(at), &settings, &mut status).unwrap();
+        }
+        recorder.process(MidiMessage::TimingClock, start, &settings, &mut status).unwrap();
+        // overdub G5 into the first step, making it the highest note of the first step
+        for message in [pedal(true), note_on(Note::G5), note_off(Note::G5), pedal(false)] {
+            recorder.process(message, start, &settings, &mut status).unwrap();
+        }
+        let recorded = match recorder.take_recording() {
+            Some((0, Recording::Synced(arp))) => arp,
+            _ => panic!("Overdub was not recorded into slot 0")
+        };
+        let file = MidiFile::parse(&recorded.to_midi_file(Arpeggio::DEFAULT_BPM).to_bytes()).unwrap();
+        let restored = Arpeggio::from_midi_file(&file, settings.finish_pattern).unwrap();
+        assert_eq!(restored.original(), Note::C4);
+
+        let mut recorder = PedalRecorder::new(&tx);
+        recorder.restore_recording(0, Recording::Synced(restored));
+        recorder.process(note_on(Note::E4), start, &settings, &mut status).unwrap();
+        while rx.try_recv().is_ok() {}
+        recorder.process(MidiMessage::TimingClock, start, &settings, &mut status).unwrap();
+        let mut played: Vec<Note> = rx.try_iter().filter_map(|(m, _)| match m {
+            MidiMessage::NoteOn(_, n, _) => Some(n),
+            _ => None
+        }).collect();
+        played.sort();
+        // transposed up a major third from C4, rather than down from G5
+        assert_eq!(played, vec![Note::E4, Note::B5]);
+    }
+}
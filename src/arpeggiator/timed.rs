@@ -9,16 +9,17 @@ use crate::arpeggio::NoteDetails;
 use crate::arpeggio::timed::{Arpeggio, Player};
 use crate::settings::Settings;
 use super::Arpeggiator;
+use super::recordings::Recording;
 
 pub struct RepeatRecorder<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     held_notes: HashMap<Note, (Instant, NoteDetails)>,
     last_note_off: Option<(Instant, NoteDetails)>,
     arpeggios: HashMap<Note, Player>
 }
 
 impl<'a> RepeatRecorder<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             held_notes: HashMap::new(),
@@ -29,21 +30,21 @@ impl<'a> RepeatRecorder<'a> {
 }
 
 impl<'a> Arpeggiator for RepeatRecorder<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::NoteOn(c, n, v) => {
                 match &self.last_note_off {
                     Some((first_i, first)) if first.n == n => {
-                        let finish = Instant::now();
+                        let finish = received_at;
                         let mut notes: Vec<(Instant, NoteDetails)> = self.held_notes.drain().map(|(_, v)| v).collect();
                         notes.push((*first_i, *first));
                         notes.sort_by(|(a, _), (b, _)| a.cmp(&b));
                         let arp = Arpeggio::from(notes, finish, settings.finish_pattern);
-                        self.arpeggios.insert(n, Player::start(arp, &self.midi_out, &settings.double_notes)?);
+                        self.arpeggios.insert(n, Player::start(arp, self.midi_out, settings)?);
                         status.reset_beat();
                     },
                     _ => {
-                        self.held_notes.insert(n, (Instant::now(), NoteDetails::new(c, n, v, settings.fixed_velocity)));
+                        self.held_notes.insert(n, (received_at, NoteDetails::new(c, n, v, settings.fixed_velocity)));
                     }
                 }
             },
@@ -76,77 +77,91 @@ impl<'a> Arpeggiator for RepeatRecorder<'a> {
 }
 
 pub struct PedalRecorder<'a> {
-    midi_out: &'a midi::OutputDevice,
+    midi_out: &'a dyn midi::MidiOut,
     notes: Vec<(Instant, NoteDetails)>,
     thru_notes: HashMap<Note, NoteDetails>,
     pedal: bool,
     arpeggios: HashMap<Note, Player>,
-    recorded: Option<Arpeggio>
+    recorded: HashMap<usize, Arpeggio>, // by slot
+    slot: usize,
+    new_recording: bool
 }
 
 impl<'a> PedalRecorder<'a> {
-    pub fn new(midi_out: &'a midi::OutputDevice) -> Self {
+    pub fn new(midi_out: &'a dyn midi::MidiOut) -> Self {
         Self {
             midi_out,
             notes: Vec::new(),
             thru_notes: HashMap::new(),
             pedal: false,
             arpeggios: HashMap::new(),
-            recorded: None
+            recorded: HashMap::new(),
+            slot: 0,
+            new_recording: false
         }
     }
 }
 
 impl<'a> Arpeggiator for PedalRecorder<'a> {
-    fn process(&mut self, received: MidiMessage<'static>, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+    fn process(&mut self, received: MidiMessage<'static>, received_at: Instant, settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
         match received {
             MidiMessage::ControlChange(_, ControlFunction::DAMPER_PEDAL, value) => {
                 if u8::from(value) >= 64 {
                     self.pedal = true;
                     status.reset_beat();
-                    self.recorded = None;
+                    self.recorded.remove(&self.slot);
                     drain_and_stop(&mut self.arpeggios);
                 } else {
                     self.pedal = false;
                     for (_, thru_note) in self.thru_notes.drain() {
-                        if self.midi_out.send(MidiMessage::NoteOff(thru_note.c, thru_note.n, thru_note.v)).is_err() {
+                        if self.midi_out.passthrough_with_settings(settings).send(MidiMessage::NoteOff(thru_note.c, thru_note.n, thru_note.v)).is_err() {
                             return Err(format!("Unable to send to output queue").into());
                         }
                     }
                     if self.notes.len() > 0 {
                         // save recorded arpeggio
-                        let finish = Instant::now();
+                        let finish = received_at;
                         let notes = mem::replace(&mut self.notes, Vec::new());
-                        self.recorded = Some(Arpeggio::from(notes, finish, settings.finish_pattern));
+                        self.recorded.insert(self.slot, Arpeggio::from(notes, finish, settings.finish_pattern));
+                        self.new_recording = true;
                         // start play in original key
-                        let arp = self.recorded.as_ref().unwrap();
+                        let arp = &self.recorded[&self.slot];
                         let original = arp.first_note();
-                        let new_arp = arp.transpose(original, original);
-                        self.arpeggios.insert(original, Player::start(new_arp, &self.midi_out, &settings.double_notes)?);
+                        let new_arp = arp.transpose(original, original, &settings.scale);
+                        self.arpeggios.insert(original, Player::start(new_arp, self.midi_out, settings)?);
                         status.reset_beat();
                     }
                 }
             },
+            MidiMessage::ControlChange(_, super::SLOT_CONTROL, value) => {
+                // recorded arpeggios which are already playing continue, but new ones use the selected slot
+                self.slot = u8::from(value) as usize;
+            },
+            MidiMessage::ControlChange(_, super::EXPORT_CONTROL, value) if u8::from(value) >= 64 => {
+                if let Some(arp) = self.recorded.get(&self.slot) {
+                    super::export_midi_file(arp.to_midi_file(), settings);
+                }
+            },
             MidiMessage::NoteOn(c, n, v) => {
                 if self.pedal {
-                    if self.midi_out.send(received).is_err() {
+                    if self.midi_out.passthrough_with_settings(settings).send(received).is_err() {
                         return Err(format!("Unable to forward to output queue").into());
                     }
                     let d = NoteDetails::new(c, n, v, settings.fixed_velocity);
                     self.thru_notes.insert(n, d);
-                    self.notes.push((Instant::now(), d));
+                    self.notes.push((received_at, d));
                 } else if self.arpeggios.contains_key(&n) {
                     // already playing, do nothing
-                } else if let Some(arp) = &self.recorded {
+                } else if let Some(arp) = self.recorded.get(&self.slot) {
                     let original = arp.first_note();
-                    let new_arp = arp.transpose(original, n);
-                    self.arpeggios.insert(n, Player::start(new_arp, &self.midi_out, &settings.double_notes)?);
+                    let new_arp = arp.transpose(original, n, &settings.scale);
+                    self.arpeggios.insert(n, Player::start(new_arp, self.midi_out, settings)?);
                     status.reset_beat();
                 }
             },
             MidiMessage::NoteOff(_, n, _) => {
                 if self.pedal {
-                    if self.midi_out.send(received).is_err() {
+                    if self.midi_out.passthrough_with_settings(settings).send(received).is_err() {
                         return Err(format!("Unable to forward to output queue").into());
                     }
                     self.thru_notes.remove(&n);
@@ -171,6 +186,20 @@ impl<'a> Arpeggiator for PedalRecorder<'a> {
     fn count_arpeggios(&self) -> usize {
         self.arpeggios.len()
     }
+
+    fn take_recording(&mut self) -> Option<(usize, Recording)> {
+        if !self.new_recording {
+            return None;
+        }
+        self.new_recording = false;
+        self.recorded.get(&self.slot).map(|arp| (self.slot, Recording::Timed(arp.clone())))
+    }
+
+    fn restore_recording(&mut self, slot: usize, recording: Recording) {
+        if let Recording::Timed(arp) = recording {
+            self.recorded.insert(slot, arp);
+        }
+    }
 }
 
 fn drain_and_stop<N>(arpeggios: &mut HashMap<N, Player>) -> Vec<Player> {
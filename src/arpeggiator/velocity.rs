@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Instant;
+use wmidi::{MidiMessage, Note};
+use crate::midi;
+use crate::settings::{Settings, VelocityLayer, VelocityMode};
+use crate::status::StatusSignal;
+use super::Arpeggiator;
+use super::recordings::Recording;
+
+/// Each layer records into its own range of slots, so recordings are kept separately for each layer
+const SLOTS_PER_LAYER: usize = 128;
+
+struct Layer<'a> {
+    min_velocity: u8,
+    settings: Settings,
+    arpeggiator: Box<dyn Arpeggiator + 'a>
+}
+
+pub struct VelocityLayers<'a> {
+    layers: Vec<Layer<'a>>,
+    mode: VelocityMode,
+    routed_notes: HashMap<Note, usize>,
+    switched_layer: usize
+}
+
+impl<'a> VelocityLayers<'a> {
+    pub fn new(midi_out: &'a dyn midi::MidiOut, settings: &Settings, velocity_layers: &[VelocityLayer], output_device_is_input_device: bool) -> Self {
+        Self {
+            layers: Self::sorted(settings, velocity_layers).into_iter().map(|(min_velocity, s)| Layer {
+                min_velocity,
+                settings: s.clone(),
+                arpeggiator: s.mode.create(midi_out, &s.presets, output_device_is_input_device)
+            }).collect(),
+            mode: settings.velocity_mode.unwrap_or(VelocityMode::Split),
+            routed_notes: HashMap::new(),
+            switched_layer: 0
+        }
+    }
+
+    /// The settings of the layer which records into this slot
+    pub fn settings_for_slot(settings: &Settings, slot: usize) -> &Settings {
+        match &settings.velocity_layers {
+            Some(velocity_layers) => Self::sorted(settings, velocity_layers).get(slot / SLOTS_PER_LAYER).map_or(settings, |(_, s)| s),
+            None => settings
+        }
+    }
+
+    /// The minimum velocity and settings of each layer, lowest first.
+    /// The top level settings are the base layer, used for any velocity below the lowest layer.
+    fn sorted<'s>(settings: &'s Settings, velocity_layers: &'s [VelocityLayer]) -> Vec<(u8, &'s Settings)> {
+        let mut layers = vec![(0, settings)];
+        layers.extend(velocity_layers.iter().map(|l| (l.min_velocity, &l.settings)));
+        layers.sort_by_key(|(min_velocity, _)| *min_velocity);
+        layers
+    }
+
+    fn layer_for(&self, velocity: u8) -> usize {
+        let mut index = 0;
+        for i in 0..self.layers.len() {
+            if velocity >= self.layers[i].min_velocity {
+                index = i;
+            }
+        }
+        index
+    }
+
+    fn process_in_layer(&mut self, index: usize, message: MidiMessage<'static>, received_at: Instant, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+        let layer = &mut self.layers[index];
+        layer.arpeggiator.process(message, received_at, &layer.settings, status)
+    }
+}
+
+impl<'a> Arpeggiator for VelocityLayers<'a> {
+    fn process(&mut self, received: MidiMessage<'static>, received_at: Instant, _settings: &Settings, status: &mut dyn StatusSignal) -> Result<(), Box<dyn Error>> {
+        match received {
+            MidiMessage::NoteOn(_, n, v) => {
+                let index = match self.mode {
+                    VelocityMode::Split => self.layer_for(v.into()),
+                    VelocityMode::Switched => {
+                        if self.routed_notes.len() == 0 {
+                            self.switched_layer = self.layer_for(v.into());
+                        }
+                        self.switched_layer
+                    }
+                };
+                self.routed_notes.insert(n, index);
+                self.process_in_layer(index, received, received_at, status)
+            },
+            MidiMessage::NoteOff(_, n, _) => {
+                // note offs go to the same layer as their note on, even if the velocity is different
+                let index = self.routed_notes.remove(&n).unwrap_or(0);
+                self.process_in_layer(index, received, received_at, status)
+            },
+            MidiMessage::PolyphonicKeyPressure(_, n, _) => {
+                let index = *self.routed_notes.get(&n).unwrap_or(&0);
+                self.process_in_layer(index, received, received_at, status)
+            },
+            _ => {
+                // clock, pedal, reset, etc are required by all layers
+                if let MidiMessage::Reset = received {
+                    self.routed_notes.clear();
+                }
+                for i in 0..self.layers.len() {
+                    self.process_in_layer(i, received.clone(), received_at, status)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn stop_arpeggios(&mut self) -> Result<(), Box<dyn Error>> {
+        for layer in self.layers.iter_mut() {
+            layer.arpeggiator.stop_arpeggios()?;
+        }
+        Ok(())
+    }
+
+    fn count_arpeggios(&self) -> usize {
+        self.layers.iter().map(|l| l.arpeggiator.count_arpeggios()).sum()
+    }
+
+    fn take_recording(&mut self) -> Option<(usize, Recording)> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if let Some((slot, recording)) = layer.arpeggiator.take_recording() {
+                return Some((i * SLOTS_PER_LAYER + slot, recording));
+            }
+        }
+        None
+    }
+
+    fn restore_recording(&mut self, slot: usize, recording: Recording) {
+        if let Some(layer) = self.layers.get_mut(slot / SLOTS_PER_LAYER) {
+            layer.arpeggiator.restore_recording(slot % SLOTS_PER_LAYER, recording);
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::fmt;
 use wmidi::{Note, MidiMessage, Velocity, Channel};
 
 use crate::midi::MidiOutput;
+use crate::scale::Scale;
+use crate::smf::Event;
 
 pub mod timed;
 pub mod synced;
@@ -66,14 +68,37 @@ impl Step {
         Ok(())
     }
 
+    /// Add the note on and off events for this step to a MIDI file track
+    fn add_to_track(&self, track: &mut Vec<(u32, Event)>, start: u32, length: u32) {
+        for note in &self.notes {
+            track.push((start, Event::Midi(MidiMessage::NoteOn(note.c, note.n, note.v))));
+            track.push((start + length, Event::Midi(MidiMessage::NoteOff(note.c, note.n, note.v))));
+        }
+    }
+
+    /// Add a note to the step, unless it is already in it
+    fn add_note(&mut self, note: NoteDetails) {
+        if !self.notes.iter().any(|d| d.c == note.c && d.n == note.n) {
+            self.notes.push(note);
+        }
+    }
+
     fn highest_note(&self) -> Option<Note> {
         self.notes.iter().map(|d| d.n).max()
     }
 
-    fn transpose(&self, half_steps: i8) -> Step {
+    fn transpose(&self, from: Note, to: Note, scale: &Option<Scale>) -> Step {
+        let from_u8: u8 = from.into();
+        let to_u8: u8 = to.into();
+        let half_steps = to_u8 as i8 - from_u8 as i8;
         let mut notes = Vec::new();
         for d in &self.notes {
-            if let Ok(new_n) = d.n.step(half_steps) {
+            let transposed = if let Some(diatonic) = scale {
+                diatonic.transpose(d.n, from, to)
+            } else {
+                d.n.step(half_steps).ok()
+            };
+            if let Some(new_n) = transposed {
                 notes.push(NoteDetails {
                     c: d.c,
                     n: new_n,
@@ -3,6 +3,7 @@ use std::fmt;
 use wmidi::{Channel, MidiMessage, Note, Velocity, U7};
 use crate::arpeggiator::Pattern;
 use crate::midi::{self, MidiOutput, TICKS_PER_BEAT};
+use crate::settings::Settings;
 
 const NOTE_MAX: usize = 127;
 
@@ -52,13 +53,13 @@ pub struct Player {
 }
 
 impl Player {
-    pub fn init(arpeggio: Arpeggio, midi_out: &midi::OutputDevice, doubling: &Option<Vec<i8>>) -> Self {
+    pub fn init(arpeggio: Arpeggio, midi_out: &dyn midi::MidiOut, settings: &Settings) -> Self {
         Self {
             arpeggio,
             last_note: NOTE_MAX - 1,
             wait_ticks: 0,
             should_stop: false,
-            midi_out: midi_out.with_doubling(doubling)
+            midi_out: midi_out.with_settings(settings)
         }
     }
 
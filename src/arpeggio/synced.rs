@@ -1,14 +1,19 @@
 use std::{sync::mpsc, error::Error};
 use std::fmt;
-use wmidi::{Note, MidiMessage, Channel, Velocity};
+use wmidi::{Note, MidiMessage, Channel, Velocity, U7};
 use crate::midi::{self, MidiOutput};
-use crate::presets::Preset;
+use crate::notename::NoteName;
+use crate::presets::{Preset, PresetStep};
+use crate::scale::Scale;
+use crate::settings::Settings;
+use crate::smf::{Event, MidiFile};
 use super::{NoteDetails, Step};
 
+#[derive(Clone)]
 pub struct Arpeggio {
-    steps: Vec<Step>,
-    ticks_per_step: usize,
-    finish_steps: bool
+    steps: Vec<(usize, Step)>, // ticks to play each step for
+    finish_steps: bool,
+    original: Option<Note> // the note it was recorded from, if that isn't its first note (eg. after overdubbing)
 }
 
 impl fmt::Display for Arpeggio {
@@ -16,19 +21,30 @@ impl fmt::Display for Arpeggio {
         match self.steps.len() {
             0 => write!(f, "-")?,
             len => {
-                write!(f, "{}", self.steps[0])?;
+                write!(f, "{}", self.steps[0].1)?;
                 for i in 1..len {
-                    write!(f, ",{}", self.steps[i])?;
+                    write!(f, ",{}", self.steps[i].1)?;
                 }
             }
         }
-        write!(f, "@{}ticks/step", self.ticks_per_step)
+        let ticks_per_step = self.steps.first().map(|(t, _)| *t).unwrap_or(0);
+        if self.steps.iter().all(|(t, _)| *t == ticks_per_step) {
+            write!(f, "@{}ticks/step", ticks_per_step)
+        } else {
+            write!(f, "@{}ticks", self.total_ticks())
+        }
     }
 }
 
 impl Arpeggio {
+    /// The tempo used for MIDI files when it is not known from MIDI clock ticks
+    pub const DEFAULT_BPM: usize = 120;
+
+    /// The text of the marker which saves the original note in MIDI files, followed by its note number
+    const ORIGINAL_MARKER: &'static str = "original note ";
+
     pub fn first_note(&self) -> Note {
-        for step in &self.steps {
+        for (_, step) in &self.steps {
             if let Some(note) = step.highest_note() {
                 return note;
             }
@@ -36,6 +52,11 @@ impl Arpeggio {
         panic!("Arpeggio did not contain any notes");
     }
 
+    /// The note this arpeggio is transposed from, which is its first note unless it has been overdubbed
+    pub fn original(&self) -> Note {
+        self.original.unwrap_or_else(|| self.first_note())
+    }
+
     pub fn from(steps: Vec<Step>, total_beats: usize, finish_steps: bool) -> Self {
         if steps.len() == 0 {
             panic!("Cannot construct an Arpeggio without any steps");
@@ -46,30 +67,132 @@ impl Arpeggio {
         } else {
             total_ticks / steps.len()
         };
-        Self { steps, ticks_per_step, finish_steps }
+        Self {
+            steps: steps.into_iter().map(|s| (ticks_per_step, s)).collect(),
+            finish_steps,
+            original: None
+        }
     }
 
-    pub fn from_preset(preset: &Preset, channel: Channel, velocity: Velocity, finish_steps: bool, notes_per_step: usize) -> Self {
-        let mut steps = Vec::new();
-        for notes in preset.steps.chunks(notes_per_step) {
-            steps.push(Step::notes(notes.iter().map(|n| NoteDetails { c: channel, n: n.into(), v: velocity }).collect()));
+    pub fn from_preset(preset: &Preset, offset: i8, channel: Channel, velocity: Velocity, finish_steps: bool, notes_per_step: usize) -> Self {
+        let default_ticks = preset.ticks_per_step.unwrap_or(1);
+        let mut steps: Vec<(usize, Step)> = Vec::new();
+        let mut plain_notes = Vec::new();
+        for preset_step in &preset.steps {
+            match preset_step {
+                PresetStep::Symbol(symbol) => plain_notes.extend(symbol.notes().iter().cloned()),
+                PresetStep::Detailed(details) => {
+                    // plain notes before a detailed step are divided into steps as usual
+                    Self::push_plain_steps(&mut steps, plain_notes.drain(..).collect(), offset, channel, velocity, default_ticks, notes_per_step);
+                    let ticks = details.ticks.unwrap_or(default_ticks);
+                    if details.tie && steps.len() > 0 {
+                        // a tie extends the previous step rather than playing new notes
+                        steps.last_mut().unwrap().0 += ticks;
+                    } else {
+                        let v = details.velocity.map(U7::from_u8_lossy).unwrap_or(velocity);
+                        let notes = Self::preset_notes(details.notes.iter().flat_map(|s| s.notes().iter()), offset, channel, v);
+                        steps.push((ticks, Step::notes(notes)));
+                    }
+                }
+            }
         }
+        Self::push_plain_steps(&mut steps, plain_notes, offset, channel, velocity, default_ticks, notes_per_step);
         Self {
             steps,
-            ticks_per_step: preset.ticks_per_step.unwrap_or(1),
-            finish_steps
+            finish_steps,
+            original: None
         }
     }
 
-    pub fn transpose(&self, from: Note, to: Note) -> Self {
-        let from_u8: u8 = from.into();
-        let to_u8: u8 = to.into();
-        let half_steps = to_u8 as i8 - from_u8 as i8;
+    fn push_plain_steps(steps: &mut Vec<(usize, Step)>, notes: Vec<NoteName>, offset: i8, channel: Channel, velocity: Velocity, ticks: usize, notes_per_step: usize) {
+        for chunk in notes.chunks(notes_per_step) {
+            steps.push((ticks, Step::notes(Self::preset_notes(chunk.iter(), offset, channel, velocity))));
+        }
+    }
+
+    fn preset_notes<'b>(names: impl Iterator<Item = &'b NoteName>, offset: i8, channel: Channel, velocity: Velocity) -> Vec<NoteDetails> {
+        // notes transposed out of range are dropped
+        names.filter_map(|n| {
+            let note: Note = n.into();
+            note.step(offset).ok()
+        }).map(|n| NoteDetails { c: channel, n, v: velocity }).collect()
+    }
+
+    pub fn transpose(&self, from: Note, to: Note, scale: &Option<Scale>) -> Self {
         Self {
-            ticks_per_step: self.ticks_per_step,
-            steps: self.steps.iter().map(|s| s.transpose(half_steps)).collect(),
-            finish_steps: self.finish_steps
+            steps: self.steps.iter().map(|(t, s)| (*t, s.transpose(from, to, scale))).collect(),
+            finish_steps: self.finish_steps,
+            original: None
+        }
+    }
+
+    /// Add notes into the steps at the given indexes, keeping the same steps, timing and original note.
+    /// The notes were played along with this arpeggio transposed from its original note to `played_from`, so are transposed back to match.
+    pub fn overdub(&self, notes: &[(usize, NoteDetails)], played_from: Note, scale: &Option<Scale>) -> Self {
+        let original = self.original();
+        let mut overdubbed = self.clone();
+        overdubbed.original = Some(original);
+        for (index, note) in notes {
+            for transposed in Step::note(*note).transpose(played_from, original, scale).notes {
+                overdubbed.steps[*index].1.add_note(transposed);
+            }
+        }
+        overdubbed
+    }
+
+    /// Convert from a MIDI file (as written by `to_midi_file`), or None if it has no notes
+    pub fn from_midi_file(file: &MidiFile, finish_steps: bool) -> Option<Self> {
+        let notes = file.notes(None, None);
+        if notes.len() == 0 {
+            return None;
+        }
+        let steps = file.steps(notes, 0, Some(file.end_ticks()), midi::TICKS_PER_BEAT as u32).into_iter()
+            .map(|s| (s.ticks as usize, Step::notes(s.notes.iter().map(|n| NoteDetails { c: n.channel, n: n.note, v: n.velocity }).collect())))
+            .collect();
+        let original = file.markers().into_iter()
+            .filter_map(|m| m.strip_prefix(Self::ORIGINAL_MARKER)?.parse::<u8>().ok())
+            .filter_map(|n| Note::try_from(n).ok())
+            .next();
+        Some(Self {
+            steps,
+            finish_steps,
+            original
+        })
+    }
+
+    /// Convert to a MIDI file with 1 tick per MIDI clock tick, at the given tempo
+    pub fn to_midi_file(&self, bpm: usize) -> MidiFile {
+        let mut track = vec![(0, Event::Tempo((60_000_000 / bpm.max(1)) as u32))];
+        if let Some(original) = self.original {
+            track.push((0, Event::Marker(format!("{}{}", Self::ORIGINAL_MARKER, u8::from(original)))));
+        }
+        let mut start = 0;
+        for (ticks, step) in &self.steps {
+            step.add_to_track(&mut track, start as u32, *ticks as u32);
+            start += ticks;
+        }
+        // keep any rests at the end
+        track.push((start as u32, Event::EndOfTrack));
+        MidiFile {
+            ticks_per_beat: midi::TICKS_PER_BEAT as u16,
+            tracks: vec![track]
+        }
+    }
+
+    fn total_ticks(&self) -> usize {
+        self.steps.iter().map(|(t, _)| t).sum()
+    }
+
+    /// The index of the step playing at this many ticks since the start, and the ticks remaining in that step
+    fn step_at(&self, ticks_since_start: usize) -> (usize, usize) {
+        let mut position = ticks_since_start % self.total_ticks();
+        for (i, (ticks, _)) in self.steps.iter().enumerate() {
+            if position < *ticks {
+                return (i, ticks - position);
+            }
+            position -= ticks;
         }
+        panic!("Ticks since start exceeded total ticks");
     }
 }
 
@@ -90,21 +213,35 @@ enum OptionIndex<T> {
 }
 
 impl Player {
-    pub fn init(arpeggio: Arpeggio, midi_out: &midi::OutputDevice, doubling: &Option<Vec<i8>>, max_repeats: Option<usize>) -> Self {
+    pub fn init(arpeggio: Arpeggio, midi_out: &dyn midi::MidiOut, settings: &Settings) -> Self {
         Self {
             arpeggio,
             step: 0,
             wait_ticks: 0,
             should_stop: false,
             last_step: OptionIndex::None,
-            midi_out: midi_out.with_doubling(doubling),
-            remaining_repeats: max_repeats
+            midi_out: midi_out.with_settings(settings),
+            remaining_repeats: settings.max_repeats
+        }
+    }
+
+    /// The index of the step being played, or the next step if it is about to be played
+    pub fn nearest_step(&self) -> usize {
+        let len = self.arpeggio.steps.len();
+        let current = match self.last_step {
+            OptionIndex::SomeIndex(index) => index,
+            _ => (self.step + len - 1) % len
+        };
+        if self.wait_ticks * 2 < self.arpeggio.steps[current].0 {
+            self.step
+        } else {
+            current
         }
     }
 
     fn last_step_off(&self) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
         match &self.last_step {
-            OptionIndex::SomeIndex(index) => self.arpeggio.steps[*index].send_off(&self.midi_out),
+            OptionIndex::SomeIndex(index) => self.arpeggio.steps[*index].1.send_off(&self.midi_out),
             OptionIndex::Some(step) => step.send_off(&self.midi_out),
             OptionIndex::None => Ok(())
         }
@@ -123,8 +260,9 @@ impl Player {
             if self.step == 0 && (self.should_stop || self.remaining_repeats == Some(0)) {
                 return Ok(false);
             }
-            self.arpeggio.steps[self.step].send_on(&self.midi_out)?;
+            self.arpeggio.steps[self.step].1.send_on(&self.midi_out)?;
             self.last_step = OptionIndex::SomeIndex(self.step);
+            self.wait_ticks = self.arpeggio.steps[self.step].0;
             if self.step == self.arpeggio.steps.len() - 1 {
                 self.step = 0;
                 if let Some(remaining) = self.remaining_repeats {
@@ -133,7 +271,6 @@ impl Player {
             } else {
                 self.step += 1;
             }
-            self.wait_ticks = self.arpeggio.ticks_per_step;
         }
         self.wait_ticks -= 1;
         Ok(true)
@@ -141,22 +278,23 @@ impl Player {
 
     pub fn change_arpeggio(&mut self, arpeggio: Arpeggio) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
         if let OptionIndex::SomeIndex(index) = self.last_step {
-            self.last_step = OptionIndex::Some(self.arpeggio.steps[index].clone());
+            self.last_step = OptionIndex::Some(self.arpeggio.steps[index].1.clone());
         }
         let steps_since_start = if self.step == 0 {
             self.arpeggio.steps.len()
         } else {
             self.step
         };
-        let ticks_since_start = steps_since_start * self.arpeggio.ticks_per_step - self.wait_ticks;
+        let ticks_since_start = self.arpeggio.steps[0..steps_since_start].iter().map(|(t, _)| t).sum::<usize>() - self.wait_ticks;
         let ticks_since_start_minus_1 = if ticks_since_start == 0 {
             0
         } else {
             ticks_since_start - 1
         };
         self.arpeggio = arpeggio;
-        self.step = (ticks_since_start_minus_1 / self.arpeggio.ticks_per_step + 1) % self.arpeggio.steps.len();
-        self.wait_ticks = self.arpeggio.ticks_per_step - (ticks_since_start_minus_1.rem_euclid(self.arpeggio.ticks_per_step) + 1);
+        let (current_step, remaining_ticks) = self.arpeggio.step_at(ticks_since_start_minus_1);
+        self.step = (current_step + 1) % self.arpeggio.steps.len();
+        self.wait_ticks = remaining_ticks - 1;
         Ok(())
     }
 
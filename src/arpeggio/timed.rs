@@ -5,9 +5,13 @@ use std::thread::{self, JoinHandle};
 use std::fmt;
 use wmidi::{Note, MidiMessage};
 use crate::midi::{self, MidiOutput};
+use crate::scale::Scale;
+use crate::settings::Settings;
+use crate::smf::{Event, MidiFile};
 
 use super::{Step, NoteDetails};
 
+#[derive(Clone)]
 pub struct Arpeggio {
     steps: Vec<(Duration, Step)>,
     period: Duration,
@@ -30,6 +34,8 @@ impl fmt::Display for Arpeggio {
 }
 
 impl Arpeggio {
+    const TICKS_PER_BEAT: u16 = 480;
+
     fn play(&self, midi_out: MidiOutput, should_stop: Arc<AtomicBool>) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
         let mut i = 0;
         while !should_stop.load(Ordering::Relaxed) || (self.finish_steps && i != 0) {
@@ -77,13 +83,50 @@ impl Arpeggio {
         Self { steps, period, finish_steps }
     }
 
-    pub fn transpose(&self, from: Note, to: Note) -> Self {
-        let from_u8: u8 = from.into();
-        let to_u8: u8 = to.into();
-        let half_steps = to_u8 as i8 - from_u8 as i8;
+    /// Convert from a MIDI file (as written by `to_midi_file`), or None if it has no notes
+    pub fn from_midi_file(file: &MidiFile, finish_steps: bool) -> Option<Self> {
+        let notes = file.notes(None, None);
+        if notes.len() == 0 {
+            return None;
+        }
+        let tick = Duration::from_micros(file.tempo() as u64) / Self::TICKS_PER_BEAT as u32;
+        let file_steps = file.steps(notes, 0, Some(file.end_ticks()), Self::TICKS_PER_BEAT as u32);
+        let mut steps: Vec<(Duration, Step)> = Vec::new();
+        let mut period = Duration::ZERO;
+        for i in 0..file_steps.len() {
+            // each step waits for the length of the previous step, with the first step waiting for the length of the last step
+            let previous = &file_steps[(i + file_steps.len() - 1) % file_steps.len()];
+            let notes = file_steps[i].notes.iter().map(|n| NoteDetails { c: n.channel, n: n.note, v: n.velocity }).collect();
+            steps.push((tick * previous.ticks, Step::notes(notes)));
+            period += tick * file_steps[i].ticks;
+        }
+        Some(Self { steps, period, finish_steps })
+    }
+
+    /// Convert to a MIDI file, at the tempo of 1 step per beat
+    pub fn to_midi_file(&self) -> MidiFile {
+        let beat = 60.0 / self.bpm();
+        let to_ticks = |d: Duration| (d.as_secs_f64() / beat * Self::TICKS_PER_BEAT as f64).round() as u32;
+        let mut track = vec![(0, Event::Tempo((beat * 1_000_000.0).round() as u32))];
+        let mut start = 0;
+        for i in 0..self.steps.len() {
+            // each step lasts until the next step, with the first step's duration being the wait after the last step,
+            // and at least 1 tick so that its notes don't end before they start (eg. the notes of a quickly played chord)
+            let length = to_ticks(self.steps[(i + 1) % self.steps.len()].0).max(1);
+            self.steps[i].1.add_to_track(&mut track, start, length);
+            start += length;
+        }
+        track.push((start, Event::EndOfTrack));
+        MidiFile {
+            ticks_per_beat: Self::TICKS_PER_BEAT,
+            tracks: vec![track]
+        }
+    }
+
+    pub fn transpose(&self, from: Note, to: Note, scale: &Option<Scale>) -> Self {
         Self {
             period: self.period,
-            steps: self.steps.iter().map(|(d, s)| (*d, s.transpose(half_steps))).collect(),
+            steps: self.steps.iter().map(|(d, s)| (*d, s.transpose(from, to, scale))).collect(),
             finish_steps: self.finish_steps
         }
     }
@@ -96,8 +139,8 @@ pub struct Player{
 }
 
 impl Player {
-    pub fn start(arpeggio: Arpeggio, midi_out: &midi::OutputDevice, doubling: &Option<Vec<i8>>) -> Result<Self, Box<dyn Error>> {
-        let output = midi_out.with_doubling(doubling);
+    pub fn start(arpeggio: Arpeggio, midi_out: &dyn midi::MidiOut, settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        let output = midi_out.with_settings(settings);
         let should_stop = Arc::new(AtomicBool::new(false));
         let should_stop_cloned = Arc::clone(&should_stop);
         let thread = thread::Builder::new().name(format!("arp:{}", arpeggio)).spawn(move || arpeggio.play(output, should_stop_cloned))?;
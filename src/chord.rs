@@ -0,0 +1,393 @@
+use std::fmt;
+use wmidi::Note;
+
+use crate::notename::PitchClass;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Power,
+    Major6,
+    Minor6,
+    Dominant7,
+    Major7,
+    Minor7,
+    MinorMajor7,
+    HalfDiminished7,
+    Diminished7,
+    Dominant7Sus4
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Extension {
+    Flat9,
+    Nine,
+    Sharp9,
+    Eleven,
+    Sharp11,
+    Flat13,
+    Thirteen
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Voicing {
+    Close,
+    Drop2,
+    Drop3,
+    Spread
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub root: PitchClass,
+    pub quality: ChordQuality,
+    pub bass: PitchClass,
+    pub extensions: Vec<Extension>
+}
+
+impl ChordQuality {
+    /// In order of preference when multiple qualities fit equally well
+    const ALL: [ChordQuality; 16] = [Self::Major7, Self::Dominant7, Self::Minor7, Self::HalfDiminished7, Self::Diminished7, Self::MinorMajor7, Self::Dominant7Sus4,
+        Self::Major, Self::Minor, Self::Major6, Self::Minor6, Self::Diminished, Self::Augmented, Self::Sus4, Self::Sus2, Self::Power];
+
+    /// Semitones above the root
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            Self::Major => &[0, 4, 7],
+            Self::Minor => &[0, 3, 7],
+            Self::Diminished => &[0, 3, 6],
+            Self::Augmented => &[0, 4, 8],
+            Self::Sus2 => &[0, 2, 7],
+            Self::Sus4 => &[0, 5, 7],
+            Self::Power => &[0, 7],
+            Self::Major6 => &[0, 4, 7, 9],
+            Self::Minor6 => &[0, 3, 7, 9],
+            Self::Dominant7 => &[0, 4, 7, 10],
+            Self::Major7 => &[0, 4, 7, 11],
+            Self::Minor7 => &[0, 3, 7, 10],
+            Self::MinorMajor7 => &[0, 3, 7, 11],
+            Self::HalfDiminished7 => &[0, 3, 6, 10],
+            Self::Diminished7 => &[0, 3, 6, 9],
+            Self::Dominant7Sus4 => &[0, 5, 7, 10]
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Major => "",
+            Self::Minor => "m",
+            Self::Diminished => "dim",
+            Self::Augmented => "aug",
+            Self::Sus2 => "sus2",
+            Self::Sus4 => "sus4",
+            Self::Power => "5",
+            Self::Major6 => "6",
+            Self::Minor6 => "m6",
+            Self::Dominant7 => "7",
+            Self::Major7 => "maj7",
+            Self::Minor7 => "m7",
+            Self::MinorMajor7 => "m(maj7)",
+            Self::HalfDiminished7 => "m7b5",
+            Self::Diminished7 => "dim7",
+            Self::Dominant7Sus4 => "7sus4"
+        }
+    }
+
+    fn has_seventh(&self) -> bool {
+        self.intervals().len() == 4 && !matches!(self, Self::Major6 | Self::Minor6)
+    }
+
+    /// Parse the quality (and any implied extensions) from the start of a chord symbol, returning the remaining text
+    fn parse_prefix(text: &str) -> (Self, &'static [Extension], &str) {
+        // longer symbols first, so that they are not mistaken for a shorter symbol followed by extensions
+        const SYMBOLS: [(&str, ChordQuality, &[Extension]); 44] = [
+            ("min(maj7)", ChordQuality::MinorMajor7, &[]),
+            ("m(maj7)", ChordQuality::MinorMajor7, &[]),
+            ("minmaj7", ChordQuality::MinorMajor7, &[]),
+            ("mmaj7", ChordQuality::MinorMajor7, &[]),
+            ("13sus4", ChordQuality::Dominant7Sus4, &[Extension::Nine, Extension::Thirteen]),
+            ("maj13", ChordQuality::Major7, &[Extension::Nine, Extension::Thirteen]),
+            ("maj11", ChordQuality::Major7, &[Extension::Nine, Extension::Eleven]),
+            ("min13", ChordQuality::Minor7, &[Extension::Nine, Extension::Thirteen]),
+            ("min11", ChordQuality::Minor7, &[Extension::Nine, Extension::Eleven]),
+            ("min7b5", ChordQuality::HalfDiminished7, &[]),
+            ("9sus4", ChordQuality::Dominant7Sus4, &[Extension::Nine]),
+            ("7sus4", ChordQuality::Dominant7Sus4, &[]),
+            ("13sus", ChordQuality::Dominant7Sus4, &[Extension::Nine, Extension::Thirteen]),
+            ("madd9", ChordQuality::Minor, &[Extension::Nine]),
+            ("min9", ChordQuality::Minor7, &[Extension::Nine]),
+            ("min7", ChordQuality::Minor7, &[]),
+            ("min6", ChordQuality::Minor6, &[]),
+            ("maj9", ChordQuality::Major7, &[Extension::Nine]),
+            ("maj7", ChordQuality::Major7, &[]),
+            ("m7b5", ChordQuality::HalfDiminished7, &[]),
+            ("dim7", ChordQuality::Diminished7, &[]),
+            ("sus2", ChordQuality::Sus2, &[]),
+            ("sus4", ChordQuality::Sus4, &[]),
+            ("add9", ChordQuality::Major, &[Extension::Nine]),
+            ("9sus", ChordQuality::Dominant7Sus4, &[Extension::Nine]),
+            ("7sus", ChordQuality::Dominant7Sus4, &[]),
+            ("m13", ChordQuality::Minor7, &[Extension::Nine, Extension::Thirteen]),
+            ("m11", ChordQuality::Minor7, &[Extension::Nine, Extension::Eleven]),
+            ("dim", ChordQuality::Diminished, &[]),
+            ("aug", ChordQuality::Augmented, &[]),
+            ("sus", ChordQuality::Sus4, &[]),
+            ("min", ChordQuality::Minor, &[]),
+            ("M7", ChordQuality::Major7, &[]),
+            ("m9", ChordQuality::Minor7, &[Extension::Nine]),
+            ("m7", ChordQuality::Minor7, &[]),
+            ("m6", ChordQuality::Minor6, &[]),
+            ("13", ChordQuality::Dominant7, &[Extension::Nine, Extension::Thirteen]),
+            ("11", ChordQuality::Dominant7, &[Extension::Nine, Extension::Eleven]),
+            ("9", ChordQuality::Dominant7, &[Extension::Nine]),
+            ("7", ChordQuality::Dominant7, &[]),
+            ("6", ChordQuality::Major6, &[]),
+            ("5", ChordQuality::Power, &[]),
+            ("m", ChordQuality::Minor, &[]),
+            ("+", ChordQuality::Augmented, &[])
+        ];
+        for (symbol, quality, extensions) in SYMBOLS {
+            if let Some(rest) = text.strip_prefix(symbol) {
+                return (quality, extensions, rest);
+            }
+        }
+        (ChordQuality::Major, &[], text)
+    }
+}
+
+impl Extension {
+    /// Semitones above the root
+    pub fn interval(&self) -> u8 {
+        match self {
+            Self::Flat9 => 13,
+            Self::Nine => 14,
+            Self::Sharp9 => 15,
+            Self::Eleven => 17,
+            Self::Sharp11 => 18,
+            Self::Flat13 => 20,
+            Self::Thirteen => 21
+        }
+    }
+
+    /// Parse an extension from the start of the text, returning the remaining text
+    fn parse_prefix(text: &str) -> Option<(Self, &str)> {
+        const SYMBOLS: [(&str, Extension); 7] = [
+            ("#11", Extension::Sharp11),
+            ("b13", Extension::Flat13),
+            ("#9", Extension::Sharp9),
+            ("b9", Extension::Flat9),
+            ("11", Extension::Eleven),
+            ("13", Extension::Thirteen),
+            ("9", Extension::Nine)
+        ];
+        for (symbol, extension) in SYMBOLS {
+            if let Some(rest) = text.strip_prefix(symbol) {
+                return Some((extension, rest));
+            }
+        }
+        None
+    }
+
+    fn from_interval(interval: u8, quality: ChordQuality) -> Option<Self> {
+        let chord_tones = quality.intervals();
+        match interval {
+            1 => Some(Self::Flat9),
+            2 => Some(Self::Nine),
+            3 if chord_tones.contains(&4) => Some(Self::Sharp9), // otherwise it would be a minor third
+            5 => Some(Self::Eleven),
+            6 if chord_tones.contains(&7) => Some(Self::Sharp11), // otherwise it would be a flat fifth
+            8 if chord_tones.contains(&7) => Some(Self::Flat13), // otherwise it would be a sharp fifth
+            9 if quality.has_seventh() => Some(Self::Thirteen), // otherwise it would be a sixth
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flat9 => write!(f, "b9"),
+            Self::Nine => write!(f, "9"),
+            Self::Sharp9 => write!(f, "#9"),
+            Self::Eleven => write!(f, "11"),
+            Self::Sharp11 => write!(f, "#11"),
+            Self::Flat13 => write!(f, "b13"),
+            Self::Thirteen => write!(f, "13")
+        }
+    }
+}
+
+impl Voicing {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "close" => Some(Self::Close),
+            "drop2" => Some(Self::Drop2),
+            "drop3" => Some(Self::Drop3),
+            "spread" => Some(Self::Spread),
+            _ => None
+        }
+    }
+}
+
+impl Chord {
+    const PERFECT_FIFTH: u8 = 7;
+
+    /// Parse a chord symbol, eg. "C", "Dm9", "Cmaj7/E", "G7(b9,#11)"
+    pub fn parse(symbol: &str) -> Result<Self, String> {
+        let (chord_text, bass_text) = match symbol.split_once('/') {
+            Some((c, b)) => (c, Some(b)),
+            None => (symbol, None)
+        };
+        let (root, rest) = PitchClass::parse_prefix(chord_text).ok_or(format!("Unknown root note in chord '{}'", symbol))?;
+        let (quality, implied, mut rest) = ChordQuality::parse_prefix(rest);
+        let mut extensions = implied.to_vec();
+        loop {
+            rest = rest.trim_start_matches(|c| c == '(' || c == ')' || c == ',');
+            if rest.len() == 0 {
+                break;
+            }
+            let (extension, after) = Extension::parse_prefix(rest).ok_or(format!("Unknown extension '{}' in chord '{}'", rest, symbol))?;
+            extensions.push(extension);
+            rest = after;
+        }
+        extensions.sort();
+        extensions.dedup();
+        let bass = match bass_text {
+            Some(text) => match PitchClass::parse_prefix(text) {
+                Some((pitch_class, "")) => pitch_class,
+                _ => return Err(format!("Unknown bass note '{}' in chord '{}'", text, symbol))
+            },
+            None => root
+        };
+        Ok(Self { root, quality, bass, extensions })
+    }
+
+    /// The notes of this chord, with the root in the given octave (where 4 contains middle C) and the bass note (if different) below the rest of the chord
+    pub fn voice(&self, octave: i8, voicing: Voicing) -> Result<Vec<Note>, String> {
+        let root = (octave as isize + 1) * 12 + self.root.semitones() as isize;
+        let bass_interval = (self.bass.semitones() + 12 - self.root.semitones()) % 12;
+        let mut notes: Vec<isize> = self.quality.intervals().iter().cloned()
+            .chain(self.extensions.iter().map(|e| e.interval()))
+            .filter(|i| bass_interval == 0 || i % 12 != bass_interval) // the bass note is not repeated in the upper voicing
+            .map(|i| root + i as isize)
+            .collect();
+        notes.sort();
+        let len = notes.len();
+        match voicing {
+            Voicing::Close => {},
+            Voicing::Drop2 => if len >= 2 {
+                notes[len - 2] -= 12;
+            },
+            Voicing::Drop3 => if len >= 3 {
+                notes[len - 3] -= 12;
+            },
+            Voicing::Spread => for i in (1..len).step_by(2) {
+                notes[i] += 12;
+            }
+        }
+        notes.sort();
+        if bass_interval != 0 {
+            let below = notes[0] - 1;
+            notes.insert(0, below - (below - self.bass.semitones() as isize).rem_euclid(12));
+        }
+        if notes.iter().any(|n| *n < 0 || *n > 127) {
+            let numbers: Vec<String> = notes.iter().map(|n| n.to_string()).collect();
+            return Err(format!("Chord '{}' in octave {} expands beyond the MIDI note range: [{}]", self, octave, numbers.join(",")));
+        }
+        Ok(notes.into_iter().map(|n| Note::from_u8_lossy(n as u8)).collect())
+    }
+
+    /// Name the chord formed by a set of notes, if any
+    pub fn recognise<'a, I: IntoIterator<Item = &'a Note>>(notes: I) -> Option<Self> {
+        let mut pitch_classes = [false; 12];
+        let mut lowest: Option<Note> = None;
+        for n in notes {
+            pitch_classes[*n as u8 as usize % 12] = true;
+            if lowest.is_none() || *n < lowest.unwrap() {
+                lowest = Some(*n);
+            }
+        }
+        let bass = PitchClass::from_semitones(lowest? as u8);
+        let mut best: Option<(isize, Chord)> = None;
+        for root in 0..12 {
+            if !pitch_classes[root] {
+                continue;
+            }
+            let intervals: Vec<u8> = (0..12).filter(|i| pitch_classes[(root + *i as usize) % 12]).collect();
+            for quality in ChordQuality::ALL {
+                if let Some((score, extensions)) = Self::fit(&intervals, quality) {
+                    let root = PitchClass::from_semitones(root as u8);
+                    let score = if root == bass { score + 1 } else { score };
+                    if best.is_none() || score > best.as_ref().unwrap().0 {
+                        best = Some((score, Chord { root, quality, bass, extensions }));
+                    }
+                }
+            }
+        }
+        best.map(|(_, chord)| chord)
+    }
+
+    /// Score how well the intervals (above a potential root) match a chord quality, returning any extra notes as extensions
+    fn fit(intervals: &Vec<u8>, quality: ChordQuality) -> Option<(isize, Vec<Extension>)> {
+        let chord_tones = quality.intervals();
+        let mut score = 10 * chord_tones.len() as isize;
+        for tone in chord_tones {
+            if !intervals.contains(tone) {
+                if *tone == Self::PERFECT_FIFTH && chord_tones.len() == 4 {
+                    // the fifth is commonly omitted from 7th chords
+                    score -= 12;
+                } else {
+                    return None;
+                }
+            }
+        }
+        let mut extensions = Vec::new();
+        for interval in intervals {
+            if !chord_tones.contains(interval) {
+                extensions.push(Extension::from_interval(*interval, quality)?);
+                score -= 3;
+            }
+        }
+        extensions.sort();
+        Some((score, extensions))
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut symbol = self.quality.symbol().to_string();
+        let mut remaining = self.extensions.clone();
+        if self.quality.has_seventh() && remaining.contains(&Extension::Nine) {
+            // name 9th, 11th and 13th chords by their highest natural extension
+            let highest = if remaining.contains(&Extension::Thirteen) {
+                Extension::Thirteen
+            } else if remaining.contains(&Extension::Eleven) {
+                Extension::Eleven
+            } else {
+                Extension::Nine
+            };
+            remaining.retain(|e| *e != Extension::Nine && *e != Extension::Eleven && *e != highest);
+            symbol = symbol.replacen("7", &highest.to_string(), 1);
+        } else if !self.quality.has_seventh() && remaining == vec![Extension::Nine] {
+            remaining.clear();
+            symbol.push_str("add9");
+        }
+        write!(f, "{}{}", self.root, symbol)?;
+        if remaining.len() > 0 {
+            write!(f, "({}", remaining[0])?;
+            for i in 1..remaining.len() {
+                write!(f, ",{}", remaining[i])?;
+            }
+            write!(f, ")")?;
+        }
+        if self.bass != self.root {
+            write!(f, "/{}", self.bass)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Devices can be chosen by the name of their sound card (eg. "name:KeyStep") or its USB vendor:product ID (eg. "usb:1c75:0288"),
+/// so that they keep the same roles whichever /dev/midi* file they are given.
+pub const NAME_PREFIX: &str = "name:";
+pub const USB_PREFIX: &str = "usb:";
+
+/// A raw MIDI device file, along with the details of the sound card it belongs to
+pub struct MidiDevice {
+    pub path: String,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub usb_id: Option<String>
+}
+
+impl fmt::Display for MidiDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(name) = self.name.as_ref().or(self.id.as_ref()) {
+            write!(f, " '{}'", name)?;
+        }
+        if let Some(usb_id) = &self.usb_id {
+            write!(f, " [{}]", usb_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl MidiDevice {
+    /// All /dev/midi* devices, sorted by path
+    pub fn list() -> Vec<Self> {
+        Self::list_from("/dev", "/sys/class/sound", "/proc/asound")
+    }
+
+    fn list_from(dev: &str, sys: &str, proc: &str) -> Vec<Self> {
+        let mut devices = Vec::new();
+        let entries = match fs::read_dir(dev) {
+            Ok(entries) => entries,
+            Err(_) => return devices
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !file_name.starts_with("midi") || entry.path().is_dir() {
+                continue;
+            }
+            let card = Self::card_number(sys, &file_name);
+            let read = |file: &str| card.and_then(|c| fs::read_to_string(Path::new(proc).join(format!("card{}", c)).join(file)).ok())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty());
+            devices.push(Self {
+                path: entry.path().display().to_string(),
+                id: read("id"),
+                name: card.and_then(|c| Self::card_name(proc, c)),
+                usb_id: read("usbid")
+            });
+        }
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+        devices
+    }
+
+    /// The sound card a MIDI device belongs to, from its sysfs link (eg. /sys/class/sound/midi1/device -> .../card1),
+    /// falling back to the number in its name (which matches the card for ALSA's OSS emulation)
+    fn card_number(sys: &str, file_name: &str) -> Option<usize> {
+        if let Ok(target) = fs::read_link(Path::new(sys).join(file_name).join("device")) {
+            if let Some(card) = target.file_name().and_then(|f| f.to_str()).and_then(|f| f.strip_prefix("card")) {
+                return card.parse().ok();
+            }
+        }
+        file_name.trim_start_matches(|c: char| !c.is_ascii_digit()).parse().ok()
+    }
+
+    /// The long name of a sound card, from the list in /proc/asound/cards (eg. " 1 [KeyStep37      ]: USB-Audio - Arturia KeyStep 37")
+    fn card_name(proc: &str, card: usize) -> Option<String> {
+        let cards = fs::read_to_string(Path::new(proc).join("cards")).ok()?;
+        for line in cards.lines() {
+            if let Some((number, rest)) = line.trim_start().split_once(' ') {
+                if number.parse() == Ok(card) {
+                    return rest.split_once(" - ").map(|(_, name)| name.trim().to_string());
+                }
+            }
+        }
+        None
+    }
+
+    fn matches(&self, selector: &str, exact: bool) -> bool {
+        if let Some(name) = selector.strip_prefix(NAME_PREFIX) {
+            let name = name.to_lowercase();
+            self.id.iter().chain(self.name.iter()).any(|n| if exact { n.to_lowercase() == name } else { n.to_lowercase().contains(&name) })
+        } else if let Some(usb_id) = selector.strip_prefix(USB_PREFIX) {
+            self.usb_id.as_ref().is_some_and(|u| u.eq_ignore_ascii_case(usb_id))
+        } else {
+            false
+        }
+    }
+}
+
+pub fn is_selector(device: &str) -> bool {
+    device.starts_with(NAME_PREFIX) || device.starts_with(USB_PREFIX)
+}
+
+/// The device file for a name/USB ID selector, or the device unchanged if it isn't a selector
+pub fn resolve(device: &str, devices: &[MidiDevice]) -> Result<String, String> {
+    if !is_selector(device) {
+        return Ok(device.to_owned());
+    }
+    // a name which matches exactly is chosen over names which only contain it, eg. "name:KeyStep" rather than "KeyStep Pro"
+    let mut matching: Vec<&MidiDevice> = devices.iter().filter(|d| d.matches(device, true)).collect();
+    if matching.is_empty() {
+        matching = devices.iter().filter(|d| d.matches(device, false)).collect();
+    }
+    match matching.as_slice() {
+        [] => Err(format!("No MIDI device matching '{}' is connected", device)),
+        [found] => Ok(found.path.clone()),
+        _ => Err(format!("'{}' matches several MIDI devices: {}", device, matching.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+/// A description of a device for status messages, including its name if it is a /dev/midi* file
+pub fn describe(device: &str, devices: &[MidiDevice]) -> String {
+    match devices.iter().find(|d| d.path == device) {
+        Some(found) => found.to_string(),
+        None => device.to_owned()
+    }
+}
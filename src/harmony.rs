@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wmidi::Note;
+
+use crate::notename::NoteName;
+use crate::scale::Scale;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Harmonizer {
+    pub intervals: Vec<i8>, // scale degrees above (or below if negative) each note, eg. 2 is a third, 5 is a sixth, -3 is a fifth below
+    #[serde(default)]
+    pub drop_2: bool, // if set, the second highest harmony note of each voicing (never the melody) is dropped by an octave
+    pub lowest: Option<NoteName>, // harmony notes below this are moved up an octave
+    pub highest: Option<NoteName>, // harmony notes above this are moved down an octave
+    #[serde(skip)]
+    voice_leading: SharedVoiceLeading
+}
+
+/// The last voicing played, so each harmony voice moves to its nearest note, and the voicing of each held melody note, so the same notes are released with it
+#[derive(Debug, Default)]
+struct VoiceLeading {
+    previous: Vec<Option<Note>>, // the last harmony note for each interval, before drop-2
+    held: HashMap<Note, (Vec<Note>, usize)> // with the number of times the melody note is held (eg. by several arpeggios)
+}
+
+/// Shared by every output using the same settings, and ignored when comparing settings
+#[derive(Debug, Clone, Default)]
+struct SharedVoiceLeading(Arc<Mutex<VoiceLeading>>);
+
+impl PartialEq for SharedVoiceLeading {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Harmonizer {
+    /// The number of times a harmony note is moved further from the melody to avoid a clash
+    const MAX_CLASH_ADJUSTMENTS: usize = 2;
+
+    /// All notes to play for this melody note (including the melody note itself), led from the previous voicing
+    pub fn note_on(&self, melody: Note, scale: &Scale) -> Vec<Note> {
+        let mut state = self.voice_leading.0.lock().unwrap();
+        if let Some((voices, count)) = state.held.get_mut(&melody) {
+            *count += 1;
+            return voices.clone();
+        }
+        let harmony = self.harmony(melody, scale, &state.previous);
+        let voices = self.voicing(melody, &harmony);
+        state.previous = harmony;
+        state.held.insert(melody, (voices.clone(), 1));
+        voices
+    }
+
+    /// The notes played for this melody note, which are released along with it
+    pub fn note_off(&self, melody: Note, scale: &Scale) -> Vec<Note> {
+        let mut state = self.voice_leading.0.lock().unwrap();
+        match state.held.get_mut(&melody) {
+            Some((voices, count)) if *count > 1 => {
+                *count -= 1;
+                voices.clone()
+            },
+            Some(_) => state.held.remove(&melody).map(|(voices, _)| voices).unwrap_or_default(),
+            None => self.voicing(melody, &self.harmony(melody, scale, &state.previous))
+        }
+    }
+
+    /// The notes being played for this melody note, eg. for polyphonic key pressure
+    pub fn held(&self, melody: Note, scale: &Scale) -> Vec<Note> {
+        let state = self.voice_leading.0.lock().unwrap();
+        match state.held.get(&melody) {
+            Some((voices, _)) => voices.clone(),
+            None => self.voicing(melody, &self.harmony(melody, scale, &state.previous))
+        }
+    }
+
+    /// The harmony note for each interval (if any), in the octave nearest to the previous harmony note for the same interval
+    fn harmony(&self, melody: Note, scale: &Scale, previous: &[Option<Note>]) -> Vec<Option<Note>> {
+        let mut voices = vec![melody];
+        let mut harmony = Vec::new();
+        for (i, interval) in self.intervals.iter().enumerate() {
+            let direction = if *interval < 0 { -1 } else { 1 };
+            let mut degrees = *interval as isize;
+            let mut note = scale.step(melody, degrees);
+            // avoid semitone clashes against the existing voices by moving further away from the melody
+            for _ in 0..Self::MAX_CLASH_ADJUSTMENTS {
+                match note {
+                    Some(n) if Self::clashes(n, &voices) => {
+                        degrees += direction;
+                        note = scale.step(melody, degrees);
+                    },
+                    _ => break
+                }
+            }
+            let note = note
+                .map(|n| match previous.get(i).cloned().flatten() {
+                    Some(p) => self.nearest(n, p),
+                    None => self.within_range(n)
+                })
+                .filter(|n| !voices.contains(n));
+            if let Some(n) = note {
+                voices.push(n);
+            }
+            harmony.push(note);
+        }
+        harmony
+    }
+
+    /// The note, or the same note an octave either side (which can move it to the other side of the melody, inverting the interval),
+    /// whichever is nearest to the previous note of the same voice and within the range
+    fn nearest(&self, note: Note, previous: Note) -> Note {
+        let in_range = |n: &Note| self.lowest.is_none_or(|l| *n >= l.into()) && self.highest.is_none_or(|h| *n <= h.into());
+        [0, -12, 12].iter()
+            .filter_map(|octave| note.step(*octave).ok())
+            .filter(in_range)
+            .min_by_key(|n| (*n as i16 - previous as i16).abs())
+            .unwrap_or_else(|| self.within_range(note))
+    }
+
+    /// The melody and harmony notes, with the second highest note dropped by an octave for drop-2 (or the next harmony note down if that is the melody)
+    fn voicing(&self, melody: Note, harmony: &[Option<Note>]) -> Vec<Note> {
+        let mut voices = vec![melody];
+        voices.extend(harmony.iter().flatten());
+        if self.drop_2 && voices.len() >= 3 {
+            let mut descending = voices.clone();
+            descending.sort_by(|a, b| b.cmp(a));
+            if let Some(dropped) = descending.iter().skip(1).find(|n| **n != melody) {
+                if let Ok(lower) = dropped.step(-12) {
+                    if !voices.contains(&lower) {
+                        voices.retain(|n| n != dropped);
+                        voices.push(lower);
+                    }
+                }
+            }
+        }
+        voices
+    }
+
+    fn clashes(note: Note, voices: &[Note]) -> bool {
+        // a minor second (or minor ninth, etc) in either direction
+        voices.iter().any(|v| matches!((*v as u8 as i8 - note as u8 as i8).rem_euclid(12), 1 | 11))
+    }
+
+    fn within_range(&self, mut note: Note) -> Note {
+        if let Some(highest) = self.highest {
+            while note > highest.into() {
+                match note.step(-12) {
+                    Ok(lower) => note = lower,
+                    Err(_) => break
+                }
+            }
+        }
+        if let Some(lowest) = self.lowest {
+            while note < lowest.into() {
+                match note.step(12) {
+                    Ok(higher) => note = higher,
+                    Err(_) => break
+                }
+            }
+        }
+        note
+    }
+}
@@ -0,0 +1,67 @@
+use std::ffi::CString;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// Watches a directory (ie. /dev) with inotify, so MIDI devices being connected or disconnected are noticed straight away,
+/// falling back to sleeping for the timeout if inotify is unavailable.
+pub struct DeviceWatcher {
+    fd: Option<libc::c_int>
+}
+
+impl DeviceWatcher {
+    pub fn new(directory: &str) -> Self {
+        match Self::watch(directory) {
+            Ok(fd) => Self {
+                fd: Some(fd)
+            },
+            Err(e) => {
+                println!("Cannot watch '{}' for MIDI devices, checking periodically instead: {}", directory, e);
+                Self {
+                    fd: None
+                }
+            }
+        }
+    }
+
+    fn watch(directory: &str) -> io::Result<libc::c_int> {
+        let path = CString::new(directory).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // ATTRIB is included because udev creates device files before setting their permissions
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB) } < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(fd)
+    }
+
+    /// Wait until a file in the directory is created, deleted or changed, or the timeout passes
+    pub fn wait(&mut self, timeout: Duration) {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return thread::sleep(timeout)
+        };
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0
+        };
+        if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } > 0 {
+            // the events themselves don't matter, just that something changed
+            let mut buf = [0u8; 4096];
+            while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+        }
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
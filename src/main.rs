@@ -1,10 +1,13 @@
 use std::time::Duration;
-use std::{env, fs, thread};
+use std::{env, fs};
 use std::error::Error;
 
 use arpeggiator::MultiArpeggiator;
 use settings::{Settings, SettingsWithProgramInfo, SpecificProgramChanges};
 use midi::{InputDevice, OutputDevice, ClockDevice};
+use routing::Routing;
+use devices::MidiDevice;
+use hotplug::DeviceWatcher;
 use status::{LedStatus, StatusSignal};
 //use crate::status::TextStatus;
 
@@ -15,10 +18,19 @@ mod settings;
 mod status;
 mod notename;
 mod presets;
+mod scale;
+mod harmony;
+mod chord;
+mod smf;
+mod routing;
+mod devices;
+mod hotplug;
 
 #[macro_use] extern crate serde_derive;
 
 const DEFAULT_SETTINGS_FILE: &str = "settings.json";
+const DEVICE_DIRECTORY: &str = "/dev";
+const WAIT_TIMEOUT: Duration = Duration::from_millis(500);
 
 fn main() -> Result<(), Box<dyn Error>> {
     let mut args = env::args().skip(1);
@@ -27,12 +39,19 @@ fn main() -> Result<(), Box<dyn Error>> {
     if let Some(midi_or_required_devices) = args.next() {
         if let Ok(required_devices) = midi_or_required_devices.parse::<usize>() {
             wait_for_midi_devices(required_devices, status, settings_list)
+        } else if midi_or_required_devices.ends_with(".json") {
+            wait_for_routed_devices(Routing::load(&midi_or_required_devices)?, status, settings_list)
         } else {
             let midi_in = midi_or_required_devices;
-            if let Some(midi_out) = args.next() {
-                run(&midi_in, &midi_out, &settings_list, &mut status)
+            let midi_out = args.next().unwrap_or(midi_in.clone());
+            let routing = Routing::between(&midi_in, &midi_out);
+            routing.validate()?;
+            if devices::is_selector(&midi_in) || devices::is_selector(&midi_out) {
+                wait_for_routed_devices(routing, status, settings_list)
             } else {
-                run(&midi_in, &midi_in, &settings_list, &mut status)
+                let default_settings = Settings::passthrough();
+                let mut settings = SpecificProgramChanges::new(&settings_list, &default_settings);
+                run(&midi_in, &midi_out, &mut settings, &mut status)
             }
         }
     } else {
@@ -42,49 +61,106 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 fn wait_for_midi_devices<S: StatusSignal>(required_devices: usize, mut status: S, settings_list: Vec<SettingsWithProgramInfo>) -> Result<(), Box<dyn Error>> {
     if required_devices < 1 || required_devices > 2 {
-        panic!("required_devices out of range 1-2")
+        return Err(format!("required_devices out of range 1-2 (use a routing file for more devices)").into());
     }
+    let mut watcher = DeviceWatcher::new(DEVICE_DIRECTORY);
+    // the settings are kept when devices reconnect
+    let default_settings = Settings::passthrough();
+    let mut settings = SpecificProgramChanges::new(&settings_list, &default_settings);
     loop {
-        let mut devices = list_files("/dev", "midi")?;
+        let mut devices = list_files(DEVICE_DIRECTORY, "midi")?;
         while devices.len() != required_devices {
             if devices.len() < required_devices {
                 status.waiting_for_midi_connect();
             } else {
                 status.waiting_for_midi_disconnect();
             }
-            thread::sleep(Duration::from_millis(500));
-            devices = list_files("/dev", "midi")?;
+            watcher.wait(WAIT_TIMEOUT);
+            devices = list_files(DEVICE_DIRECTORY, "midi")?;
         }
         status.waiting_for_midi_clock();
         if devices.len() == 1 {
-            run_and_print(&devices[0], &devices[0], &settings_list, &mut status);
+            run_and_print(&devices[0], &devices[0], &mut settings, &mut status);
         } else if ClockDevice::init(&devices[0]).is_ok() {
-            run_and_print(&devices[1], &devices[0], &settings_list, &mut status);
+            run_and_print(&devices[1], &devices[0], &mut settings, &mut status);
         } else if ClockDevice::init(&devices[1]).is_ok() {
-            run_and_print(&devices[0], &devices[1], &settings_list, &mut status);
+            run_and_print(&devices[0], &devices[1], &mut settings, &mut status);
+        }
+    }
+}
+
+fn wait_for_routed_devices<S: StatusSignal>(routing: Routing, mut status: S, settings_list: Vec<SettingsWithProgramInfo>) -> Result<(), Box<dyn Error>> {
+    let mut watcher = DeviceWatcher::new(DEVICE_DIRECTORY);
+    // the settings are kept when devices reconnect
+    let default_settings = Settings::passthrough();
+    let mut settings = SpecificProgramChanges::new(&settings_list, &default_settings);
+    let mut reported = Vec::new();
+    loop {
+        let devices = MidiDevice::list();
+        let resolved = match routing.resolve(&devices) {
+            Ok(resolved) => resolved,
+            Err(missing) => {
+                if missing != reported {
+                    println!("Waiting for MIDI devices to connect: {}", missing.join(", "));
+                    let connected: Vec<String> = devices.iter().map(|d| d.to_string()).collect();
+                    println!("Connected MIDI devices: {}", if connected.is_empty() { "(none)".to_owned() } else { connected.join(", ") });
+                    reported = missing;
+                }
+                status.waiting_for_midi_connect();
+                watcher.wait(WAIT_TIMEOUT);
+                continue;
+            }
+        };
+        reported.clear();
+        if resolved.external_clock().is_some() {
+            status.waiting_for_midi_clock();
         }
+        match run_routed(&resolved, &devices, &mut settings, &mut status) {
+            Ok(()) => println!("Arpeggiator disconnected OK"),
+            Err(e) => println!("Arpeggiator disconnected with error: {}", e)
+        }
+        watcher.wait(WAIT_TIMEOUT);
     }
 }
 
-fn run_and_print<SS: StatusSignal>(midi_in: &str, midi_out_with_clock: &str, settings_list: &Vec<SettingsWithProgramInfo>, status: &mut SS) {
-    match run(midi_in, midi_out_with_clock, settings_list, status) {
+fn run_routed<SS: StatusSignal>(routing: &Routing, devices: &[MidiDevice], settings: &mut SpecificProgramChanges, status: &mut SS) -> Result<(), Box<dyn Error>> {
+    let describe_all = |list: &Vec<String>| list.iter().map(|d| devices::describe(d, devices)).collect::<Vec<_>>().join(", ");
+    println!("Starting arpeggiator with MIDI-IN: {}, Clock: {}, MIDI-OUT: {}",
+        describe_all(&routing.inputs),
+        routing.clock.as_ref().map_or("(inputs)".to_owned(), |c| devices::describe(c, devices)),
+        describe_all(&routing.outputs.iter().map(|o| o.device().clone()).collect()));
+    let midi_out = OutputDevice::open_all(&routing.filtered_outputs())?;
+    MultiArpeggiator {
+        midi_in: InputDevice::open_all(&routing.inputs, routing.clock.as_deref(), true)?.until_disconnected(&midi_out),
+        midi_out,
+        output_device_is_input_device: routing.output_device_is_input_device(),
+        settings,
+        status
+    }.listen()
+}
+
+fn run_and_print<SS: StatusSignal>(midi_in: &str, midi_out_with_clock: &str, settings: &mut SpecificProgramChanges, status: &mut SS) {
+    match run(midi_in, midi_out_with_clock, settings, status) {
         Ok(()) => println!("Arpeggiator disconnected OK"),
         Err(e) => println!("Arpeggiator disconnected with error: {}", e)
     }
 }
 
-fn run<SS: StatusSignal>(midi_in: &str, midi_out: &str, settings_list: &Vec<SettingsWithProgramInfo>, status: &mut SS) -> Result<(), Box<dyn Error>> {
-    println!("Starting arpeggiator with MIDI-IN: {}, MIDI-OUT: {}", midi_in, midi_out);
-    let default_settings = Settings::passthrough();
+fn run<SS: StatusSignal>(midi_in: &str, midi_out: &str, settings: &mut SpecificProgramChanges, status: &mut SS) -> Result<(), Box<dyn Error>> {
+    let devices = MidiDevice::list();
+    println!("Starting arpeggiator with MIDI-IN: {}, MIDI-OUT: {}", devices::describe(midi_in, &devices), devices::describe(midi_out, &devices));
+    let output_device_is_input_device = midi_in == midi_out;
+    let midi_in = if output_device_is_input_device || !midi::can_send_clock(midi_out) {
+        InputDevice::open(midi_in, true)?
+    } else {
+        InputDevice::open_with_external_clock(midi_in, midi_out, true)?
+    };
+    let midi_out = OutputDevice::open(midi_out)?;
     MultiArpeggiator {
-        midi_in: if midi_in == midi_out {
-            InputDevice::open(&midi_in, true)?
-        } else {
-            InputDevice::open_with_external_clock(&midi_in, &midi_out, true)?
-        },
-        midi_out: OutputDevice::open(&midi_out)?,
-        output_device_is_input_device: midi_in == midi_out,
-        settings: SpecificProgramChanges::new(settings_list, &default_settings),
+        midi_in: midi_in.until_disconnected(&midi_out),
+        midi_out,
+        output_device_is_input_device,
+        settings,
         status
     }.listen()
 }
@@ -1,109 +1,244 @@
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::path::Path;
+use std::ffi::CString;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::fs;
 use std::thread;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::error::Error;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use wmidi::ControlFunction;
-use wmidi::FromBytesError;
 use wmidi::MidiMessage;
 use wmidi::Note;
 use wmidi::U7;
 use nonblock::NonBlockingReader;
 
+use crate::harmony::Harmonizer;
+use crate::scale::Scale;
+use crate::settings::Settings;
+use self::alsa::Sequencer;
+use self::rtp::{RtpSink, RtpSource};
+use self::filter::{OutputFilter, Source};
+use self::parser::ByteStream;
+
+pub mod alsa;
+pub mod rtp;
+pub mod filter;
+pub mod parser;
+
 pub trait MidiReceiver {
     fn passthrough_midi(&mut self, message: MidiMessage<'static>) -> Option<MidiMessage<'static>> {
         Some(message)
     }
 }
 
+/// Where the arpeggiator reads its MIDI messages from, along with when they were received
+pub trait MidiIn {
+    fn read(&mut self) -> Result<(MidiMessage<'static>, Instant), Box<dyn Error>>;
+}
+
+/// Where the arpeggiator (and its arpeggios, on their own threads) send their MIDI messages to
+pub trait MidiOut {
+    fn sender(&self) -> mpsc::Sender<(MidiMessage<'static>, Source)>;
+
+    fn with_settings(&self, settings: &Settings) -> MidiOutput {
+        MidiOutput::new(self.sender(), Source::Arpeggio, settings)
+    }
+
+    /// For messages passed through from MIDI-IN, rather than played by an arpeggio
+    fn passthrough_with_settings(&self, settings: &Settings) -> MidiOutput {
+        MidiOutput::new(self.sender(), Source::Passthrough, settings)
+    }
+}
+
+/// A transport which MIDI messages are received from, returning None once it has disconnected
+pub trait MidiSource: Send {
+    fn receive(&mut self) -> Option<MidiMessage<'static>>;
+}
+
+/// A transport which MIDI messages are sent over
+pub trait MidiSink: Send {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>>;
+}
+
 pub struct InputDevice {
-    receiver: mpsc::Receiver<MidiMessage<'static>>,
-    threads: Vec<JoinHandle<()>>
+    receiver: mpsc::Receiver<(MidiMessage<'static>, Instant)>,
+    threads: Vec<JoinHandle<()>>,
+    outputs_disconnected: Vec<Arc<AtomicBool>>
 }
 
 pub struct ClockDevice {
-    path: PathBuf
+    device: String
 }
 
 pub struct OutputDevice {
-    sender: mpsc::Sender<MidiMessage<'static>>,
-    thread: JoinHandle<()>
+    sender: mpsc::Sender<(MidiMessage<'static>, Source)>,
+    thread: JoinHandle<()>,
+    disconnected: Arc<AtomicBool> // set when any of its devices disconnects
 }
 
 pub const TICKS_PER_BEAT: usize = 24;
 
+/// Device arguments starting with this prefix are named pipes, which are created if they don't exist
+pub const FIFO_PREFIX: &str = "fifo:";
+
+/// Named pipes, virtual ports and network sessions are only written to, so clock-ticks have to be sent to the MIDI-IN device instead
+pub fn can_send_clock(midi_out: &str) -> bool {
+    !midi_out.starts_with(FIFO_PREFIX) && !midi_out.starts_with(rtp::PREFIX) && !alsa::is_virtual(midi_out)
+}
+
+fn open_file(device: &str, write: bool) -> io::Result<fs::File> {
+    if let Some(path) = device.strip_prefix(FIFO_PREFIX) {
+        if !Path::new(path).exists() {
+            let c_path = CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if unsafe { libc::mkfifo(c_path.as_ptr(), 0o666) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // opening both ends means this doesn't block waiting for the other end, and doesn't disconnect when it closes
+        fs::File::options().read(true).write(true).open(path)
+    } else {
+        fs::File::options().read(!write).write(write).open(device)
+    }
+}
+
+fn open_source(midi_in: &str) -> Result<Box<dyn MidiSource>, Box<dyn Error>> {
+    if let Some(address) = midi_in.strip_prefix(alsa::PREFIX) {
+        Ok(Box::new(Sequencer::open_input(address)?))
+    } else if let Some(port) = midi_in.strip_prefix(rtp::PREFIX) {
+        Ok(Box::new(RtpSource::open(port)?))
+    } else {
+        Ok(Box::new(ByteStream::new(open_file(midi_in, false).map_err(|e| format!("Cannot open MIDI IN '{}': {}", midi_in, e))?)))
+    }
+}
+
+fn open_sink(midi_out: &str) -> Result<Box<dyn MidiSink>, Box<dyn Error>> {
+    if let Some(address) = midi_out.strip_prefix(alsa::PREFIX) {
+        Ok(Box::new(Sequencer::open_output(address)?))
+    } else if let Some(port) = midi_out.strip_prefix(rtp::PREFIX) {
+        Ok(Box::new(RtpSink::open(port)?))
+    } else {
+        Ok(Box::new(open_file(midi_out, true).map_err(|e| format!("Cannot open MIDI OUT '{}': {}", midi_out, e))?))
+    }
+}
+
+impl<R: Read + Send> MidiSource for ByteStream<R> {
+    fn receive(&mut self) -> Option<MidiMessage<'static>> {
+        self.next_message()
+    }
+}
+
+impl<W: Write + Send> MidiSink for W {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        let expected = message.bytes_size();
+        let mut buf = vec![0; expected];
+        match message.copy_to_slice(&mut buf) {
+            Ok(found) if found != expected => return Err(format!("Not enough bytes (expected {} found {}).", expected, found).into()),
+            Err(_) => return Err(format!("Too many bytes (expected {}).", expected).into()),
+            _ => {}
+        }
+        self.write_all(&buf)?;
+        self.flush()?;
+        Ok(())
+    }
+}
+
+/// An in-memory channel, eg. for testing or connecting arpeggiators together
+impl MidiIn for mpsc::Receiver<(MidiMessage<'static>, Instant)> {
+    fn read(&mut self) -> Result<(MidiMessage<'static>, Instant), Box<dyn Error>> {
+        Ok(self.recv()?)
+    }
+}
+
+impl MidiOut for mpsc::Sender<(MidiMessage<'static>, Source)> {
+    fn sender(&self) -> mpsc::Sender<(MidiMessage<'static>, Source)> {
+        self.clone()
+    }
+}
+
 impl InputDevice {
+    const DISCONNECT_CHECK: Duration = Duration::from_millis(100);
+
     pub fn open(midi_in: &str, include_clock_ticks: bool) -> Result<Self, Box<dyn Error>> {
+        Self::from_source(open_source(midi_in)?, include_clock_ticks)
+    }
+
+    /// Read from any transport, on its own thread
+    pub fn from_source(mut source: Box<dyn MidiSource>, include_clock_ticks: bool) -> Result<Self, Box<dyn Error>> {
         let (tx, rx) = mpsc::channel();
-        let mut input = fs::File::options().read(true).open(midi_in).map_err(|e| format!("Cannot open MIDI IN '{}': {}", midi_in, e))?;
-        let join_handle = thread::Builder::new().name(format!("midi-in")).spawn(move || Self::read_into_queue(&mut input, tx, include_clock_ticks, true))?;
+        let join_handle = thread::Builder::new().name("midi-in".to_owned()).spawn(move || Self::read_into_queue(&mut source, tx, include_clock_ticks, true))?;
         Ok(Self {
             receiver: rx,
-            threads: vec![join_handle]
+            threads: vec![join_handle],
+            outputs_disconnected: Vec::new()
         })
     }
 
     pub fn open_with_external_clock(midi_in: &str, clock_in: &str, include_msb_lsb_prog_change_from_clock: bool) -> Result<Self, Box<dyn Error>> {
+        Self::open_all(&[midi_in.to_owned()], Some(clock_in), include_msb_lsb_prog_change_from_clock)
+    }
+
+    /// Read from several devices at once, with clock ticks only from the clock device (if set)
+    pub fn open_all(midi_ins: &[String], clock_in: Option<&str>, include_msb_lsb_prog_change_from_clock: bool) -> Result<Self, Box<dyn Error>> {
         let (tx, rx) = mpsc::channel();
-        let include_clock_ticks = midi_in == clock_in;
-        let mut input = fs::File::options().read(true).open(midi_in).map_err(|e| format!("Cannot open MIDI IN '{}': {}", midi_in, e))?;
-        let clock = ClockDevice::init(clock_in)?;
+        let mut inputs = Vec::new();
+        for midi_in in midi_ins {
+            inputs.push((open_source(midi_in)?, clock_in.is_none_or(|c| c == midi_in)));
+        }
         let mut threads = Vec::new();
-        if !include_clock_ticks {
+        // a clock device which is also an input is already open, so its clock ticks are read along with its notes
+        if let Some(clock_in) = clock_in.filter(|c| !midi_ins.iter().any(|m| m == c)) {
+            let clock = ClockDevice::init(clock_in)?;
             threads.push(clock.connect(tx.clone(), include_msb_lsb_prog_change_from_clock)?);
         }
-        threads.push(thread::Builder::new().name(format!("midi-in")).spawn(move || Self::read_into_queue(&mut input, tx, include_clock_ticks, true))?);
+        for (mut input, include_clock_ticks) in inputs {
+            let tx = tx.clone();
+            threads.push(thread::Builder::new().name("midi-in".to_owned()).spawn(move || Self::read_into_queue(&mut input, tx, include_clock_ticks, true))?);
+        }
         Ok(Self {
             receiver: rx,
-            threads
+            threads,
+            outputs_disconnected: Vec::new()
         })
     }
 
-    pub fn read(&mut self) -> Result<MidiMessage<'static>, Box<dyn Error>> {
-        for thread in &self.threads {
-            if thread.is_finished() {
-                // this needs to be an error, because self.receiver can be receiving from multiple senders,
-                // and we need to consider this device as finished if either source disconnects
-                return Err("Input thread has finished".into());
-            }
+    /// Also stop reading when the output device disconnects, so that the arpeggiator restarts and waits for it to reconnect
+    pub fn until_disconnected(mut self, midi_out: &OutputDevice) -> Self {
+        self.outputs_disconnected.push(midi_out.disconnected.clone());
+        self
+    }
+
+    fn check_connected(&self) -> Result<(), Box<dyn Error>> {
+        if self.threads.iter().any(|t| t.is_finished()) {
+            // this needs to be an error, because self.receiver can be receiving from multiple senders,
+            // and we need to consider this device as finished if either source disconnects
+            return Err("Input thread has finished".into());
         }
-        let message = self.receiver.recv()?;
-        Ok(message)
+        if self.outputs_disconnected.iter().any(|d| d.load(Ordering::Relaxed)) {
+            return Err("Output device has disconnected".into());
+        }
+        Ok(())
     }
 
-    fn read_into_queue(f: &mut fs::File, tx: mpsc::Sender<MidiMessage>, include_clock_ticks: bool, rewrite_note_zero_as_off: bool) {
-        let mut buf: [u8; 1] = [0; 1];
-        let mut bytes = Vec::new();
-        while f.read_exact(&mut buf).is_ok() {
-            bytes.push(buf[0]);
-            match MidiMessage::try_from(bytes.as_slice()) {
-                Ok(MidiMessage::TimingClock) if !include_clock_ticks => {
+    fn read_into_queue(source: &mut Box<dyn MidiSource>, tx: mpsc::Sender<(MidiMessage, Instant)>, include_clock_ticks: bool, rewrite_note_zero_as_off: bool) {
+        while let Some(message) = source.receive() {
+            // timestamped here, so the time spent waiting in the queue doesn't affect recordings
+            let received_at = Instant::now();
+            match message {
+                MidiMessage::TimingClock if !include_clock_ticks => {
                     // skip clock tick if not required
-                    bytes.clear();
                 },
-                Ok(MidiMessage::NoteOn(c, n, U7::MIN)) if rewrite_note_zero_as_off => {
+                MidiMessage::NoteOn(c, n, U7::MIN) if rewrite_note_zero_as_off => {
                     // some keyboards send NoteOn(velocity: 0) instead of NoteOff (eg. Kaysound MK-4902)
-                    if let Err(e) = tx.send(MidiMessage::NoteOff(c, n, U7::MIN)) {
+                    if let Err(e) = tx.send((MidiMessage::NoteOff(c, n, U7::MIN), received_at)) {
                         panic!("Error rewriting NoteOn(0) as NoteOff to input queue: {}", e);
                     }
-                    bytes.clear();
                 },
-                Ok(message) => {
-                    // message complete, send to queue
-                    if let Err(e) = tx.send(message.to_owned()) {
+                _ => {
+                    if let Err(e) = tx.send((message, received_at)) {
                         panic!("Error sending to input queue: {}", e);
                     }
-                    bytes.clear();
-                },
-                Err(FromBytesError::NoBytes) | Err(FromBytesError::NoSysExEndByte) | Err(FromBytesError::NotEnoughBytes) => {
-                    // wait for more bytes
-                }, 
-                _ => {
-                    // invalid message, clear and wait for next message
-                    bytes.clear();
                 }
             }
         }
@@ -111,13 +246,26 @@ impl InputDevice {
     }
 }
 
+impl MidiIn for InputDevice {
+    fn read(&mut self) -> Result<(MidiMessage<'static>, Instant), Box<dyn Error>> {
+        self.check_connected()?;
+        loop {
+            match self.receiver.recv_timeout(Self::DISCONNECT_CHECK) {
+                Ok(message) => return Ok(message),
+                // notice a disconnected device straight away, rather than when the next message arrives
+                Err(mpsc::RecvTimeoutError::Timeout) => self.check_connected()?,
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+}
 
 impl ClockDevice {
     const MIDI_TICK: u8 = 0xF8;
     
     pub fn init(midi_clock: &str) -> Result<Self, Box<dyn Error>> {
         let mut clock = Self {
-            path: PathBuf::from(midi_clock)
+            device: midi_clock.to_owned()
         };
         clock.wait_for_tick(1000)?;
         Ok(clock)
@@ -125,8 +273,35 @@ impl ClockDevice {
 
     pub fn wait_for_tick(&mut self, timeout_ms: u64) -> Result<(), Box<dyn Error>> {
         const SLEEP_MS: u64 = 100;
-        let f = fs::File::options().read(true).open(&self.path)
-            .map_err(|e| format!("Cannot open Clock device '{}': {}", self.path.display(), e))?;
+        if let Some(address) = self.device.strip_prefix(alsa::PREFIX) {
+            let mut seq = Sequencer::open_input(address)?;
+            seq.set_nonblocking()?;
+            let mut elapsed = 0;
+            while elapsed < timeout_ms {
+                while let Some(message) = seq.try_read()? {
+                    if message == MidiMessage::TimingClock {
+                        // tick detected
+                        return Ok(());
+                    }
+                }
+                thread::sleep(Duration::from_millis(SLEEP_MS));
+                elapsed += SLEEP_MS;
+            }
+            return Err(format!("Clock device did not send a clock signal within {}ms: {}", timeout_ms, self.device).into());
+        }
+        if let Some(port) = self.device.strip_prefix(rtp::PREFIX) {
+            let mut session = RtpSource::open(port)?;
+            let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+            while let Some(message) = session.receive_timeout(deadline.saturating_duration_since(Instant::now())) {
+                if message == MidiMessage::TimingClock {
+                    // tick detected
+                    return Ok(());
+                }
+            }
+            return Err(format!("Clock device did not send a clock signal within {}ms: {}", timeout_ms, self.device).into());
+        }
+        let f = open_file(&self.device, false)
+            .map_err(|e| format!("Cannot open Clock device '{}': {}", self.device, e))?;
         let mut noblock = NonBlockingReader::from_fd(f)?;
         let mut elapsed = 0;
         while !noblock.is_eof() && elapsed < timeout_ms {
@@ -142,162 +317,159 @@ impl ClockDevice {
             elapsed += SLEEP_MS;
         }
         if noblock.is_eof() {
-            Err(format!("Clock device disconnected: {}", self.path.display()).into())
+            Err(format!("Clock device disconnected: {}", self.device).into())
         } else {
-            Err(format!("Clock device did not send a clock signal within {}ms: {}", timeout_ms, self.path.display()).into())
+            Err(format!("Clock device did not send a clock signal within {}ms: {}", timeout_ms, self.device).into())
         }
     }
 
-    pub fn connect(self, sender: mpsc::Sender<MidiMessage<'static>>, include_msb_lsb_program_change: bool) -> Result<JoinHandle<()>, Box<dyn Error>> {
-        let mut clock = fs::File::options().read(true).open(&self.path)
-            .map_err(|e| format!("Cannot open Clock device '{}': {}", self.path.display(), e))?;
-        if include_msb_lsb_program_change {
-            Ok(thread::Builder::new().name(format!("midi-clock")).spawn(move || Self::read_clocks_and_prog_change_into_queue(&mut clock, sender))?)
-        } else {
-            Ok(thread::Builder::new().name(format!("midi-clock")).spawn(move || Self::read_clocks_into_queue(&mut clock, sender))?)
-        }
+    pub fn connect(self, sender: mpsc::Sender<(MidiMessage<'static>, Instant)>, include_msb_lsb_program_change: bool) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        let mut clock = open_source(&self.device).map_err(|e| format!("Cannot open Clock device '{}': {}", self.device, e))?;
+        Ok(thread::Builder::new().name("midi-clock".to_owned()).spawn(move || Self::read_clocks_into_queue(&mut clock, sender, include_msb_lsb_program_change))?)
     }
 
-    fn read_clocks_into_queue(f: &mut fs::File, tx: mpsc::Sender<MidiMessage>) {
-        let mut buf: [u8; 1] = [0; 1];
-        while f.read_exact(&mut buf).is_ok() {
-            if buf[0] == Self::MIDI_TICK {
-                // tick detected, send to queue
-                if let Err(e) = tx.send(MidiMessage::TimingClock) {
+    fn read_clocks_into_queue(source: &mut Box<dyn MidiSource>, tx: mpsc::Sender<(MidiMessage, Instant)>, include_msb_lsb_program_change: bool) {
+        while let Some(message) = source.receive() {
+            let received_at = Instant::now();
+            let wanted = match message {
+                MidiMessage::TimingClock => true,
+                MidiMessage::ControlChange(_, ControlFunction::BANK_SELECT, _)
+                    | MidiMessage::ControlChange(_, ControlFunction::BANK_SELECT_LSB, _)
+                    | MidiMessage::ProgramChange(_, _) => include_msb_lsb_program_change,
+                _ => false
+            };
+            if wanted {
+                if let Err(e) = tx.send((message, received_at)) {
                     panic!("Error sending clock to queue: {}", e);
                 }
             }
         }
         println!("Clock device has disconnected");
     }
-
-    fn read_clocks_and_prog_change_into_queue(f: &mut fs::File, tx: mpsc::Sender<MidiMessage>) {
-        let mut buf: [u8; 1] = [0; 1];
-        let mut bytes = Vec::new();
-        while f.read_exact(&mut buf).is_ok() {
-            bytes.push(buf[0]);
-            match MidiMessage::try_from(bytes.as_slice()) {
-                Ok(MidiMessage::TimingClock) => {
-                    if let Err(e) = tx.send(MidiMessage::TimingClock) {
-                        panic!("Error sending clock to queue: {}", e);
-                    }
-                    bytes.clear();
-                },
-                Ok(MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT, msb)) => {
-                    if let Err(e) = tx.send(MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT, msb)) {
-                        panic!("Error sending MSB to queue: {}", e);
-                    }
-                    bytes.clear();
-                },
-                Ok(MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT_LSB, lsb)) => {
-                    if let Err(e) = tx.send(MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT_LSB, lsb)) {
-                        panic!("Error sending LSB to queue: {}", e);
-                    }
-                    bytes.clear();
-                },
-                Ok(MidiMessage::ProgramChange(ch, pc)) => {
-                    if let Err(e) = tx.send(MidiMessage::ProgramChange(ch, pc)) {
-                        panic!("Error sending PC to queue: {}", e);
-                    }
-                    bytes.clear();
-                },
-                Err(FromBytesError::NoBytes) | Err(FromBytesError::NoSysExEndByte) | Err(FromBytesError::NotEnoughBytes) => {
-                    // wait for more bytes
-                }, 
-                _ => {
-                    // invalid (or unwanted) message, clear and wait for next message
-                    bytes.clear();
-                }
-            }
-        }
-        println!("Clock device has disconnected");
-    }
 }
 
 impl OutputDevice {
     pub fn open(midi_out: &str) -> Result<Self, Box<dyn Error>> {
-        let (tx, rx) = mpsc::channel();
-        let mut output = fs::File::options().write(true).open(midi_out).map_err(|e| format!("Cannot open MIDI OUT '{}': {}", midi_out, e))?;
-        let thread = thread::Builder::new().name(format!("midi-out")).spawn(move || Self::write_from_queue(&mut output, rx))?;
-        Ok(Self {
-            sender: tx,
-            thread
-        })
+        Self::from_sinks(vec![(open_sink(midi_out)?, OutputFilter::default())])
     }
 
-    pub fn send(&self, message: MidiMessage<'static>) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
-        if self.thread.is_finished() {
-            println!("Output thread has finished");
+    /// Send messages to several devices at once, each only getting the messages its filter allows
+    pub fn open_all(midi_outs: &[(String, OutputFilter)]) -> Result<Self, Box<dyn Error>> {
+        let mut sinks = Vec::new();
+        for (midi_out, filter) in midi_outs {
+            sinks.push((open_sink(midi_out)?, filter.clone()));
         }
-        self.sender.send(message)
-    }
-
-    pub fn with_doubling(&self, doubling: &Option<Vec<i8>>) -> MidiOutput {
-        MidiOutput::new(self.sender.clone(), if let Some(d) = doubling { d.clone() } else { Vec::new() })
+        Self::from_sinks(sinks)
     }
 
-    pub fn send_with_doubling<'a, I: Iterator<Item = &'a i8>>(&self, message: MidiMessage<'static>, doubling: I) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
-        MidiOutput::send_doubles(message, &self.sender, doubling)
+    /// Write to any transports, on their own thread
+    pub fn from_sinks(mut sinks: Vec<(Box<dyn MidiSink>, OutputFilter)>) -> Result<Self, Box<dyn Error>> {
+        let (tx, rx) = mpsc::channel();
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let thread_disconnected = disconnected.clone();
+        let thread = thread::Builder::new().name("midi-out".to_owned()).spawn(move || Self::write_from_queue(&mut sinks, rx, &thread_disconnected))?;
+        Ok(Self {
+            sender: tx,
+            thread,
+            disconnected
+        })
     }
 
-    fn write_from_queue(f: &mut fs::File, rx: mpsc::Receiver<MidiMessage>) {
-        let mut buf = Vec::new();
-        for received in rx {
-            let expected = received.bytes_size();
-            buf.resize(expected, 0);
-            match received.copy_to_slice(&mut buf) {
-                Ok(found) if found != expected => panic!("Error writing midi message: Not enough bytes (expected {} found {}).", expected, found),
-                Err(_) => panic!("Error writing midi message: Too many bytes (expected {}).", expected),
-                _ => {}
-            }
-            if let Err(e) = f.write_all(&buf) {
-                panic!("Error writing to output device: {}", e);
-            }
-            if let Err(e) = f.flush() {
-                panic!("Error flushing output device: {}", e);
+    fn write_from_queue(sinks: &mut Vec<(Box<dyn MidiSink>, OutputFilter)>, rx: mpsc::Receiver<(MidiMessage<'static>, Source)>, disconnected: &AtomicBool) {
+        for (received, source) in rx {
+            // keep writing to the remaining devices if one disconnects, so they still get All Notes Off while the arpeggiator restarts
+            sinks.retain_mut(|(sink, filter)| match filter.apply(&received, source).map_or(Ok(()), |message| sink.send(&message)) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Error writing to output device: {}", e);
+                    disconnected.store(true, Ordering::Relaxed);
+                    false
+                }
+            });
+            if sinks.is_empty() {
+                break;
             }
         }
         println!("Output device has disconnected");
     }
 }
 
+impl MidiOut for OutputDevice {
+    fn sender(&self) -> mpsc::Sender<(MidiMessage<'static>, Source)> {
+        if self.thread.is_finished() {
+            println!("Output thread has finished");
+        }
+        self.sender.clone()
+    }
+}
+
 pub struct MidiOutput {
-    sender: mpsc::Sender<MidiMessage<'static>>,
-    doubling: Vec<i8>
+    sender: mpsc::Sender<(MidiMessage<'static>, Source)>,
+    source: Source,
+    doubling: Vec<i8>,
+    scale: Option<Scale>,
+    harmonizer: Option<(Harmonizer, Scale)>
 }
 
 impl MidiOutput {
-    fn new(sender: mpsc::Sender<MidiMessage<'static>>, doubling: Vec<i8>) -> Self {
+    fn new(sender: mpsc::Sender<(MidiMessage<'static>, Source)>, source: Source, settings: &Settings) -> Self {
         Self {
             sender,
-            doubling
+            source,
+            doubling: settings.double_notes.clone().unwrap_or_default(),
+            scale: settings.scale.clone(),
+            harmonizer: settings.harmonizer.as_ref().map(|h| (h.clone(), settings.scale.clone().unwrap_or(Scale::C_MAJOR)))
         }
     }
 
     pub fn send(&self, message: MidiMessage<'static>) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
-        Self::send_doubles(message, &self.sender, self.doubling.iter())
-    }
-
-    fn send_doubles<'a, I: Iterator<Item = &'a i8>>(message: MidiMessage<'static>, sender: &mpsc::Sender<MidiMessage<'static>>, doubling: I) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
         match message {
-            MidiMessage::NoteOff(c, n, v) => for i in doubling {
-                if let Some(t) = Self::transpose(n, i) {
-                    sender.send(MidiMessage::NoteOff(c, t, v))?;
-                }
+            MidiMessage::NoteOff(c, n, v) => for t in self.output_notes(n, Harmonizer::note_off) {
+                self.send_tagged(MidiMessage::NoteOff(c, t, v))?;
             },
-            MidiMessage::NoteOn(c, n, v) => for i in doubling {
-                if let Some(t) = Self::transpose(n, i) {
-                    sender.send(MidiMessage::NoteOn(c, t, v))?;
-                }
+            MidiMessage::NoteOn(c, n, v) => for t in self.output_notes(n, Harmonizer::note_on) {
+                self.send_tagged(MidiMessage::NoteOn(c, t, v))?;
             },
-            MidiMessage::PolyphonicKeyPressure(c, n, v) => for i in doubling {
-                if let Some(t) = Self::transpose(n, i) {
-                    sender.send(MidiMessage::PolyphonicKeyPressure(c, t, v))?;
-                }
+            MidiMessage::PolyphonicKeyPressure(c, n, v) => for t in self.output_notes(n, Harmonizer::held) {
+                self.send_tagged(MidiMessage::PolyphonicKeyPressure(c, t, v))?;
             },
-            _ => {}
+            _ => self.send_tagged(message)?
+        }
+        Ok(())
+    }
+
+    fn send_tagged(&self, message: MidiMessage<'static>) -> Result<(), mpsc::SendError<MidiMessage<'static>>> {
+        self.sender.send((message, self.source)).map_err(|e| mpsc::SendError(e.0.0))
+    }
+
+    fn output_notes(&self, note: Note, harmonize: fn(&Harmonizer, Note, &Scale) -> Vec<Note>) -> Vec<Note> {
+        let original = self.quantize(note);
+        let voices = if let Some((harmonizer, key)) = &self.harmonizer {
+            harmonize(harmonizer, original, key)
+        } else {
+            vec![original]
+        };
+        let mut notes = Vec::new();
+        for voice in &voices {
+            for delta in &self.doubling {
+                if let Some(t) = Self::transpose(*voice, delta) {
+                    let t = self.quantize(t);
+                    if !voices.contains(&t) && !notes.contains(&t) {
+                        notes.push(t);
+                    }
+                }
+            }
+        }
+        notes.extend(voices);
+        notes
+    }
+
+    fn quantize(&self, note: Note) -> Note {
+        if let Some(scale) = &self.scale {
+            scale.quantize(note)
+        } else {
+            note
         }
-        sender.send(message)
     }
 
     fn transpose(note: Note, delta: &i8) -> Option<Note> {
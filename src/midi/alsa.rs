@@ -0,0 +1,290 @@
+//! MIDI ports on the ALSA sequencer, as an alternative to the raw /dev/midi* files.
+//! Ports are addressed as CLIENT:PORT (eg. "20:0" or "Arturia KeyStep 37:0", as listed by `aconnect -l`),
+//! or as "virtual" (or "virtual:NAME") to create an unconnected port which other software can connect to.
+//! Building with `--features alsa` links against libasound, otherwise opening a port returns an error.
+
+use std::error::Error;
+use wmidi::MidiMessage;
+use super::{MidiSink, MidiSource};
+
+/// Device arguments starting with this prefix are ALSA sequencer addresses rather than file paths
+pub const PREFIX: &str = "alsa:";
+
+pub use self::sequencer::Sequencer;
+
+const VIRTUAL: &str = "virtual";
+
+impl MidiSource for Sequencer {
+    fn receive(&mut self) -> Option<MidiMessage<'static>> {
+        self.read()
+    }
+}
+
+impl MidiSink for Sequencer {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        self.write(message)
+    }
+}
+
+pub fn is_virtual(device: &str) -> bool {
+    match device.strip_prefix(PREFIX).and_then(|address| address.strip_prefix(VIRTUAL)) {
+        Some(name) => name.is_empty() || name.starts_with(':'),
+        None => false
+    }
+}
+
+#[cfg(not(feature = "alsa"))]
+mod sequencer {
+    use std::error::Error;
+    use wmidi::MidiMessage;
+
+    pub struct Sequencer;
+
+    impl Sequencer {
+        pub fn open_input(address: &str) -> Result<Self, Box<dyn Error>> {
+            Err(format!("Cannot open ALSA MIDI IN '{}': ALSA support was not included in this build (build with --features alsa)", address).into())
+        }
+
+        pub fn open_output(address: &str) -> Result<Self, Box<dyn Error>> {
+            Err(format!("Cannot open ALSA MIDI OUT '{}': ALSA support was not included in this build (build with --features alsa)", address).into())
+        }
+
+        pub fn read(&mut self) -> Option<MidiMessage<'static>> {
+            None
+        }
+
+        pub fn try_read(&mut self) -> Result<Option<MidiMessage<'static>>, Box<dyn Error>> {
+            Ok(None)
+        }
+
+        pub fn set_nonblocking(&mut self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        pub fn write(&mut self, _message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "alsa")]
+mod sequencer {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int, c_long, c_uchar, c_uint, c_void};
+    use std::error::Error;
+    use std::ptr;
+    use wmidi::MidiMessage;
+    use super::VIRTUAL;
+
+    const SND_SEQ_OPEN_OUTPUT: c_int = 1;
+    const SND_SEQ_OPEN_INPUT: c_int = 2;
+    const SND_SEQ_NONBLOCK: c_int = 1;
+    const SND_SEQ_PORT_CAP_READ: c_uint = 1 << 0;
+    const SND_SEQ_PORT_CAP_WRITE: c_uint = 1 << 1;
+    const SND_SEQ_PORT_CAP_SUBS_READ: c_uint = 1 << 5;
+    const SND_SEQ_PORT_CAP_SUBS_WRITE: c_uint = 1 << 6;
+    const SND_SEQ_PORT_TYPE_MIDI_GENERIC: c_uint = 1 << 1;
+    const SND_SEQ_PORT_TYPE_APPLICATION: c_uint = 1 << 20;
+    const SND_SEQ_ADDRESS_SUBSCRIBERS: u8 = 254;
+    const SND_SEQ_ADDRESS_UNKNOWN: u8 = 253;
+    const SND_SEQ_QUEUE_DIRECT: u8 = 253;
+    const SND_SEQ_EVENT_NONE: u8 = 0;
+    const CLIENT_NAME: &str = "midi-arpeggiator";
+    const BUFFER_SIZE: usize = 256;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct SeqAddr {
+        client: u8,
+        port: u8
+    }
+
+    /// snd_seq_event_t, whose data is only read and written by snd_midi_event_decode/encode
+    #[repr(C)]
+    #[derive(Default)]
+    struct SeqEvent {
+        event_type: u8,
+        flags: u8,
+        tag: u8,
+        queue: u8,
+        time: [u32; 2],
+        source: SeqAddr,
+        dest: SeqAddr,
+        data: [u32; 3]
+    }
+
+    // libasound reads and writes the whole of snd_seq_event_t, so the layout must match its 28 bytes
+    const _: () = assert!(std::mem::size_of::<SeqEvent>() == 28);
+
+    #[link(name = "asound")]
+    extern "C" {
+        fn snd_seq_open(handle: *mut *mut c_void, name: *const c_char, streams: c_int, mode: c_int) -> c_int;
+        fn snd_seq_close(handle: *mut c_void) -> c_int;
+        fn snd_seq_nonblock(handle: *mut c_void, nonblock: c_int) -> c_int;
+        fn snd_seq_set_client_name(handle: *mut c_void, name: *const c_char) -> c_int;
+        fn snd_seq_create_simple_port(handle: *mut c_void, name: *const c_char, caps: c_uint, port_type: c_uint) -> c_int;
+        fn snd_seq_parse_address(handle: *mut c_void, addr: *mut SeqAddr, arg: *const c_char) -> c_int;
+        fn snd_seq_connect_from(handle: *mut c_void, my_port: c_int, src_client: c_int, src_port: c_int) -> c_int;
+        fn snd_seq_connect_to(handle: *mut c_void, my_port: c_int, dest_client: c_int, dest_port: c_int) -> c_int;
+        fn snd_seq_event_input(handle: *mut c_void, ev: *mut *mut SeqEvent) -> c_int;
+        fn snd_seq_event_output_direct(handle: *mut c_void, ev: *mut SeqEvent) -> c_int;
+        fn snd_midi_event_new(bufsize: usize, rdev: *mut *mut c_void) -> c_int;
+        fn snd_midi_event_free(dev: *mut c_void);
+        fn snd_midi_event_no_status(dev: *mut c_void, on: c_int);
+        fn snd_midi_event_reset_encode(dev: *mut c_void);
+        fn snd_midi_event_encode(dev: *mut c_void, buf: *const c_uchar, count: c_long, ev: *mut SeqEvent) -> c_long;
+        fn snd_midi_event_decode(dev: *mut c_void, buf: *mut c_uchar, count: c_long, ev: *const SeqEvent) -> c_long;
+        fn snd_strerror(errnum: c_int) -> *const c_char;
+    }
+
+    /// A sequencer client with a single port, connected to one other client:port (unless virtual)
+    pub struct Sequencer {
+        handle: *mut c_void,
+        codec: *mut c_void,
+        port: c_int,
+        buf: Vec<u8>
+    }
+
+    // the sequencer handle is only ever used by the thread which owns it
+    unsafe impl Send for Sequencer {}
+
+    impl Sequencer {
+        pub fn open_input(address: &str) -> Result<Self, Box<dyn Error>> {
+            let seq = Self::open(Self::virtual_name(address).unwrap_or(CLIENT_NAME), SND_SEQ_OPEN_INPUT, "midi-in", SND_SEQ_PORT_CAP_WRITE | SND_SEQ_PORT_CAP_SUBS_WRITE)
+                .map_err(|e| format!("Cannot open ALSA MIDI IN '{}': {}", address, e))?;
+            if Self::virtual_name(address).is_some() {
+                return Ok(seq);
+            }
+            let addr = seq.parse_address(address).map_err(|e| format!("Cannot open ALSA MIDI IN '{}': {}", address, e))?;
+            check(unsafe { snd_seq_connect_from(seq.handle, seq.port, addr.client as c_int, addr.port as c_int) })
+                .map_err(|e| format!("Cannot connect from ALSA MIDI IN '{}': {}", address, e))?;
+            Ok(seq)
+        }
+
+        pub fn open_output(address: &str) -> Result<Self, Box<dyn Error>> {
+            let seq = Self::open(Self::virtual_name(address).unwrap_or(CLIENT_NAME), SND_SEQ_OPEN_OUTPUT, "midi-out", SND_SEQ_PORT_CAP_READ | SND_SEQ_PORT_CAP_SUBS_READ)
+                .map_err(|e| format!("Cannot open ALSA MIDI OUT '{}': {}", address, e))?;
+            if Self::virtual_name(address).is_some() {
+                return Ok(seq);
+            }
+            let addr = seq.parse_address(address).map_err(|e| format!("Cannot open ALSA MIDI OUT '{}': {}", address, e))?;
+            check(unsafe { snd_seq_connect_to(seq.handle, seq.port, addr.client as c_int, addr.port as c_int) })
+                .map_err(|e| format!("Cannot connect to ALSA MIDI OUT '{}': {}", address, e))?;
+            Ok(seq)
+        }
+
+        /// The client name for a virtual port, which defaults to the name of the arpeggiator
+        fn virtual_name(address: &str) -> Option<&str> {
+            match address {
+                VIRTUAL => Some(CLIENT_NAME),
+                _ => address.strip_prefix(VIRTUAL)?.strip_prefix(':')
+            }
+        }
+
+        fn open(client_name: &str, streams: c_int, port_name: &str, caps: c_uint) -> Result<Self, String> {
+            let mut handle = ptr::null_mut();
+            let name = CString::new("default").unwrap();
+            check(unsafe { snd_seq_open(&mut handle, name.as_ptr(), streams, 0) })?;
+            let mut codec = ptr::null_mut();
+            if let Err(e) = check(unsafe { snd_midi_event_new(BUFFER_SIZE, &mut codec) }) {
+                unsafe { snd_seq_close(handle) };
+                return Err(e);
+            }
+            let mut seq = Self {
+                handle,
+                codec,
+                port: 0,
+                buf: vec![0; BUFFER_SIZE]
+            };
+            unsafe { snd_midi_event_no_status(seq.codec, 1) };
+            let client_name = CString::new(client_name).map_err(|e| e.to_string())?;
+            check(unsafe { snd_seq_set_client_name(seq.handle, client_name.as_ptr()) })?;
+            let port_name = CString::new(port_name).unwrap();
+            seq.port = check(unsafe { snd_seq_create_simple_port(seq.handle, port_name.as_ptr(), caps, SND_SEQ_PORT_TYPE_MIDI_GENERIC | SND_SEQ_PORT_TYPE_APPLICATION) })?;
+            Ok(seq)
+        }
+
+        fn parse_address(&self, address: &str) -> Result<SeqAddr, String> {
+            let mut addr = SeqAddr::default();
+            let arg = CString::new(address).map_err(|e| e.to_string())?;
+            check(unsafe { snd_seq_parse_address(self.handle, &mut addr, arg.as_ptr()) })?;
+            Ok(addr)
+        }
+
+        /// Blocks until a MIDI message is received, returning None if the sequencer fails
+        pub fn read(&mut self) -> Option<MidiMessage<'static>> {
+            loop {
+                match self.try_read() {
+                    Ok(Some(message)) => return Some(message),
+                    Ok(None) => {},
+                    Err(_) => return None
+                }
+            }
+        }
+
+        /// Reads the next event, returning None if it was not a MIDI message (or none was available in non-blocking mode)
+        pub fn try_read(&mut self) -> Result<Option<MidiMessage<'static>>, Box<dyn Error>> {
+            let mut event = ptr::null_mut();
+            match unsafe { snd_seq_event_input(self.handle, &mut event) } {
+                e if e == -libc::EAGAIN || e == -libc::ENOSPC => return Ok(None), // nothing available, or input overrun
+                e if e < 0 => return Err(error_string(e).into()),
+                _ => {}
+            }
+            let length = unsafe { snd_midi_event_decode(self.codec, self.buf.as_mut_ptr(), self.buf.len() as c_long, event) };
+            if length <= 0 {
+                // not a MIDI message (eg. a port subscription)
+                return Ok(None);
+            }
+            Ok(MidiMessage::try_from(&self.buf[0..length as usize]).ok().map(|m| m.to_owned()))
+        }
+
+        pub fn set_nonblocking(&mut self) -> Result<(), Box<dyn Error>> {
+            check(unsafe { snd_seq_nonblock(self.handle, SND_SEQ_NONBLOCK) })?;
+            Ok(())
+        }
+
+        pub fn write(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+            let length = message.bytes_size();
+            self.buf.resize(length.max(BUFFER_SIZE), 0);
+            message.copy_to_slice(&mut self.buf[0..length]).map_err(|_| "Too many bytes")?;
+            let mut event = SeqEvent::default();
+            unsafe { snd_midi_event_reset_encode(self.codec) };
+            let encoded = unsafe { snd_midi_event_encode(self.codec, self.buf.as_ptr(), length as c_long, &mut event) };
+            if encoded < 0 {
+                return Err(error_string(encoded as c_int).into());
+            }
+            if event.event_type == SND_SEQ_EVENT_NONE {
+                return Err(format!("Cannot encode MIDI message ({} bytes)", length).into());
+            }
+            event.source.port = self.port as u8;
+            event.dest = SeqAddr {
+                client: SND_SEQ_ADDRESS_SUBSCRIBERS,
+                port: SND_SEQ_ADDRESS_UNKNOWN
+            };
+            event.queue = SND_SEQ_QUEUE_DIRECT;
+            check(unsafe { snd_seq_event_output_direct(self.handle, &mut event) })?;
+            Ok(())
+        }
+    }
+
+    impl Drop for Sequencer {
+        fn drop(&mut self) {
+            unsafe {
+                snd_midi_event_free(self.codec);
+                snd_seq_close(self.handle);
+            }
+        }
+    }
+
+    fn check(result: c_int) -> Result<c_int, String> {
+        if result < 0 {
+            Err(error_string(result))
+        } else {
+            Ok(result)
+        }
+    }
+
+    fn error_string(errnum: c_int) -> String {
+        unsafe { CStr::from_ptr(snd_strerror(errnum)) }.to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,103 @@
+use wmidi::{Channel, MidiMessage, Note};
+
+use crate::notename::NoteName;
+
+/// Whether a message was played by an arpeggio (or chord), or passed through from MIDI-IN
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    Arpeggio,
+    Passthrough,
+    #[serde(skip)]
+    Reset // eg. All Notes Off when an input disconnects, which every output gets whatever its filter
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum MessageType {
+    Notes, // including polyphonic key pressure
+    Controls,
+    ProgramChanges,
+    ChannelPressure,
+    PitchBend,
+    Other
+}
+
+/// Which messages are sent to an output device, and which channel they are sent on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OutputFilter {
+    pub source: Option<Source>, // if set, only messages from this source are sent
+    pub messages: Option<Vec<MessageType>>, // if set, only these types of messages are sent
+    pub lowest: Option<NoteName>, // if set, notes below this are not sent
+    pub highest: Option<NoteName>, // if set, notes above this are not sent
+    pub channel: Option<u8> // 1-16, if set, all channel messages are sent on this channel instead
+}
+
+impl MessageType {
+    fn of(message: &MidiMessage) -> Self {
+        match message {
+            MidiMessage::NoteOff(_, _, _) | MidiMessage::NoteOn(_, _, _) | MidiMessage::PolyphonicKeyPressure(_, _, _) => Self::Notes,
+            MidiMessage::ControlChange(_, _, _) => Self::Controls,
+            MidiMessage::ProgramChange(_, _) => Self::ProgramChanges,
+            MidiMessage::ChannelPressure(_, _) => Self::ChannelPressure,
+            MidiMessage::PitchBendChange(_, _) => Self::PitchBend,
+            _ => Self::Other
+        }
+    }
+}
+
+impl OutputFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(channel) = self.channel {
+            if channel < 1 || channel > 16 {
+                return Err(format!("Channel {} is out of range 1-16", channel));
+            }
+        }
+        if let (Some(lowest), Some(highest)) = (self.lowest, self.highest) {
+            if lowest > highest {
+                return Err(format!("Lowest note ({:?}) is above highest note ({:?})", lowest, highest));
+            }
+        }
+        Ok(())
+    }
+
+    /// The message to send to the output device, if any
+    pub fn apply(&self, message: &MidiMessage<'static>, source: Source) -> Option<MidiMessage<'static>> {
+        if source == Source::Reset {
+            // resets are sent on every channel, so an output with its own channel only needs the one for that channel
+            return match self.channel {
+                Some(channel) if message.channel().map(|c| c.number()) != Some(channel) => None,
+                _ => Some(message.clone())
+            };
+        }
+        if self.source.is_some_and(|s| s != source) {
+            return None;
+        }
+        if let Some(messages) = &self.messages {
+            if !messages.contains(&MessageType::of(message)) {
+                return None;
+            }
+        }
+        let in_range = |n: &Note| self.lowest.is_none_or(|l| *n >= l.into()) && self.highest.is_none_or(|h| *n <= h.into());
+        match message {
+            MidiMessage::NoteOff(_, n, _) | MidiMessage::NoteOn(_, n, _) | MidiMessage::PolyphonicKeyPressure(_, n, _) if !in_range(n) => return None,
+            _ => {}
+        }
+        match self.channel {
+            Some(channel) => Some(Self::remap(message.clone(), Channel::from_index(channel - 1).ok()?)),
+            None => Some(message.clone())
+        }
+    }
+
+    fn remap(message: MidiMessage<'static>, c: Channel) -> MidiMessage<'static> {
+        match message {
+            MidiMessage::NoteOff(_, n, v) => MidiMessage::NoteOff(c, n, v),
+            MidiMessage::NoteOn(_, n, v) => MidiMessage::NoteOn(c, n, v),
+            MidiMessage::PolyphonicKeyPressure(_, n, v) => MidiMessage::PolyphonicKeyPressure(c, n, v),
+            MidiMessage::ControlChange(_, f, v) => MidiMessage::ControlChange(c, f, v),
+            MidiMessage::ProgramChange(_, p) => MidiMessage::ProgramChange(c, p),
+            MidiMessage::ChannelPressure(_, v) => MidiMessage::ChannelPressure(c, v),
+            MidiMessage::PitchBendChange(_, v) => MidiMessage::PitchBendChange(c, v),
+            other => other
+        }
+    }
+}
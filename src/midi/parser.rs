@@ -0,0 +1,152 @@
+use std::io::Read;
+use wmidi::MidiMessage;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// Parses raw MIDI bytes one at a time, as they arrive from a device
+#[derive(Default)]
+pub struct Parser {
+    status: Option<u8>, // kept after channel messages, for running status
+    data: Vec<u8>
+}
+
+/// Raw MIDI bytes, eg. from /dev/midi* files, named pipes or sockets
+pub struct ByteStream<R: Read> {
+    reader: R,
+    parser: Parser
+}
+
+impl Parser {
+    /// The message completed by this byte, if any
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage<'static>> {
+        match byte {
+            0xF8..=0xFF => {
+                // real-time messages can be sent between the bytes of another message, without interrupting it
+                Self::message(&[byte])
+            },
+            SYSEX_END => {
+                if self.status != Some(SYSEX_START) {
+                    // end without a start, so there's nothing to complete
+                    self.status = None;
+                    self.data.clear();
+                    return None;
+                }
+                self.status = None;
+                let mut bytes = vec![SYSEX_START];
+                bytes.append(&mut self.data);
+                bytes.push(SYSEX_END);
+                Self::message(&bytes)
+            },
+            0x80..=0xF6 => {
+                // a new status byte abandons any incomplete message (including SysEx without an end)
+                self.status = Some(byte);
+                self.data.clear();
+                self.complete()
+            },
+            _ => {
+                // data without a status, eg. after connecting part way through a message
+                self.status?;
+                self.data.push(byte);
+                self.complete()
+            }
+        }
+    }
+
+    fn complete(&mut self) -> Option<MidiMessage<'static>> {
+        let status = self.status?;
+        let data_length = match status {
+            0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+            0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+            SYSEX_START => return None, // SysEx is any length, so only completes at its end
+            _ => 0
+        };
+        if self.data.len() < data_length {
+            return None;
+        }
+        let mut bytes = vec![status];
+        bytes.append(&mut self.data);
+        if status >= 0xF0 {
+            // system common messages cancel running status
+            self.status = None;
+        }
+        Self::message(&bytes)
+    }
+
+    fn message(bytes: &[u8]) -> Option<MidiMessage<'static>> {
+        MidiMessage::try_from(bytes).ok().map(|m| m.to_owned())
+    }
+}
+
+impl<R: Read> ByteStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            parser: Parser::default()
+        }
+    }
+
+    /// The next message, or None once the reader has disconnected
+    pub fn next_message(&mut self) -> Option<MidiMessage<'static>> {
+        let mut buf: [u8; 1] = [0; 1];
+        while self.reader.read_exact(&mut buf).is_ok() {
+            if let Some(message) = self.parser.push(buf[0]) {
+                return Some(message);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut parser = Parser::default();
+        bytes.iter().filter_map(|b| parser.push(*b)).map(|message| {
+            let mut buf = vec![0; message.bytes_size()];
+            message.copy_to_slice(&mut buf).unwrap();
+            buf
+        }).collect()
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(parse(&[0x90, 0x3C, 0x40, 0x3E, 0x41, 0xC0, 0x05, 0x06]),
+            vec![vec![0x90, 0x3C, 0x40], vec![0x90, 0x3E, 0x41], vec![0xC0, 0x05], vec![0xC0, 0x06]]);
+    }
+
+    #[test]
+    fn real_time_between_data_bytes() {
+        assert_eq!(parse(&[0x90, 0xF8, 0x3C, 0xF8, 0x40, 0x3E, 0xFE, 0x41]),
+            vec![vec![0xF8], vec![0xF8], vec![0x90, 0x3C, 0x40], vec![0xFE], vec![0x90, 0x3E, 0x41]]);
+    }
+
+    #[test]
+    fn sysex_with_real_time_inside() {
+        let mut sysex = vec![0xF0];
+        sysex.extend((0..1000).map(|i| (i % 128) as u8));
+        sysex.push(0xF7);
+        let mut bytes = sysex.clone();
+        bytes.insert(10, 0xF8);
+        bytes.insert(500, 0xFA);
+        assert_eq!(parse(&bytes), vec![vec![0xF8], vec![0xFA], sysex]);
+    }
+
+    #[test]
+    fn stray_sysex_end() {
+        assert_eq!(parse(&[0xF7, 0x90, 0x3C, 0x40, 0xF7, 0x3E, 0x41]), vec![vec![0x90, 0x3C, 0x40]]);
+    }
+
+    #[test]
+    fn data_without_status() {
+        assert_eq!(parse(&[0x3C, 0x40, 0x01, 0xB0, 0x07, 0x64]), vec![vec![0xB0, 0x07, 0x64]]);
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        assert_eq!(parse(&[0x90, 0x3C, 0x40, 0xF2, 0x01, 0x02, 0x3E, 0x41, 0xF6, 0x3C, 0x40]),
+            vec![vec![0x90, 0x3C, 0x40], vec![0xF2, 0x01, 0x02], vec![0xF6]]);
+    }
+}
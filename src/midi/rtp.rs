@@ -0,0 +1,382 @@
+//! Network MIDI using RTP-MIDI (RFC 6295) with the AppleMIDI session protocol, as supported by macOS "Network MIDI" and rtpMIDI on Windows.
+//! The arpeggiator listens for a session invitation on a UDP control port (and the port after it for data),
+//! then receives MIDI from (and sends MIDI to) whichever peer most recently connected.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use wmidi::MidiMessage;
+
+use super::{MidiSink, MidiSource};
+
+/// Device arguments starting with this prefix are RTP-MIDI sessions, eg. "rtp:5004"
+pub const PREFIX: &str = "rtp:";
+
+const SESSION_NAME: &str = "midi-arpeggiator";
+const PROTOCOL_VERSION: u32 = 2;
+const RTP_VERSION: u8 = 0x80;
+const MIDI_PAYLOAD_TYPE: u8 = 0x61;
+const MAX_PACKET: usize = 1500;
+const MAX_QUEUED: usize = 1024;
+
+/// Messages received by the session, waiting to be read by the MIDI-IN device
+pub struct RtpSource {
+    receiver: mpsc::Receiver<MidiMessage<'static>>
+}
+
+/// Sends messages to the connected peer (or drops them if no peer is connected)
+pub struct RtpSink {
+    session: Arc<Session>,
+    sequence: u16
+}
+
+struct Session {
+    data: UdpSocket,
+    ssrc: u32,
+    start: Instant,
+    peer: Mutex<Option<Peer>>,
+    sender: Mutex<Option<mpsc::SyncSender<MidiMessage<'static>>>>
+}
+
+#[derive(Clone, Copy)]
+struct Peer {
+    ssrc: u32,
+    data: SocketAddr
+}
+
+impl RtpSource {
+    pub fn open(port: &str) -> Result<Self, Box<dyn Error>> {
+        let session = Session::get(port)?;
+        // this replaces the channel of any previous source, which then disconnects so its thread finishes,
+        // allowing the session to be read again after the arpeggiator restarts
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUED);
+        *session.sender.lock().unwrap() = Some(tx);
+        Ok(Self {
+            receiver: rx
+        })
+    }
+
+    /// The next message, or None if nothing is received within the timeout
+    pub fn receive_timeout(&mut self, timeout: Duration) -> Option<MidiMessage<'static>> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl MidiSource for RtpSource {
+    fn receive(&mut self) -> Option<MidiMessage<'static>> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl RtpSink {
+    pub fn open(port: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            session: Session::get(port)?,
+            sequence: 0
+        })
+    }
+}
+
+impl MidiSink for RtpSink {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        let peer = match *self.session.peer.lock().unwrap() {
+            Some(peer) => peer,
+            None => return Ok(()) // nobody is listening
+        };
+        let mut midi = vec![0; message.bytes_size()];
+        message.copy_to_slice(&mut midi).map_err(|_| "Too many bytes")?;
+        let mut packet = vec![RTP_VERSION, MIDI_PAYLOAD_TYPE];
+        packet.extend(self.sequence.to_be_bytes());
+        packet.extend(self.session.timestamp().to_be_bytes());
+        packet.extend(self.session.ssrc.to_be_bytes());
+        if midi.len() < 0x10 {
+            packet.push(midi.len() as u8);
+        } else if midi.len() < 0x1000 {
+            // long header (B flag) with a 12 bit length
+            packet.push(0x80 | (midi.len() >> 8) as u8);
+            packet.push(midi.len() as u8);
+        } else {
+            return Err(format!("MIDI message too long for RTP-MIDI ({} bytes)", midi.len()).into());
+        }
+        packet.extend(midi);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.session.data.send_to(&packet, peer.data)?;
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Sessions are shared by port, so the same session can be both MIDI-IN and MIDI-OUT
+    fn get(port: &str) -> Result<Arc<Self>, Box<dyn Error>> {
+        static SESSIONS: OnceLock<Mutex<HashMap<u16, Arc<Session>>>> = OnceLock::new();
+        let port: u16 = port.parse().map_err(|_| format!("Invalid RTP-MIDI port '{}'", port))?;
+        let mut sessions = SESSIONS.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+        if let Some(session) = sessions.get(&port) {
+            return Ok(session.clone());
+        }
+        let session = Self::listen(port)?;
+        sessions.insert(port, session.clone());
+        Ok(session)
+    }
+
+    fn listen(port: u16) -> Result<Arc<Self>, Box<dyn Error>> {
+        let data_port = port.checked_add(1).ok_or_else(|| format!("Invalid RTP-MIDI port {} (the port after it is needed for data)", port))?;
+        let control = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("Cannot listen for RTP-MIDI on port {}: {}", port, e))?;
+        let data = UdpSocket::bind(("0.0.0.0", data_port)).map_err(|e| format!("Cannot listen for RTP-MIDI on port {}: {}", data_port, e))?;
+        let session = Arc::new(Self {
+            data: data.try_clone()?,
+            ssrc: Self::random_ssrc(),
+            start: Instant::now(),
+            peer: Mutex::new(None),
+            sender: Mutex::new(None)
+        });
+        let control_session = session.clone();
+        thread::Builder::new().name("rtp-control".to_owned()).spawn(move || control_session.handle_packets(control, false))?;
+        let data_session = session.clone();
+        thread::Builder::new().name("rtp-data".to_owned()).spawn(move || data_session.handle_packets(data, true))?;
+        println!("Listening for RTP-MIDI sessions on port {}", port);
+        Ok(session)
+    }
+
+    fn handle_packets(&self, socket: UdpSocket, is_data: bool) {
+        let mut buf = [0; MAX_PACKET];
+        loop {
+            let (length, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("RTP-MIDI session has disconnected: {}", e);
+                    return;
+                }
+            };
+            let packet = &buf[0..length];
+            let result = match packet {
+                [0xFF, 0xFF, b'I', b'N', ..] => self.accept(&socket, from, packet, is_data),
+                [0xFF, 0xFF, b'C', b'K', ..] => self.synchronize(&socket, from, packet),
+                [0xFF, 0xFF, b'B', b'Y', ..] => {
+                    self.end(packet);
+                    Ok(())
+                },
+                [0xFF, 0xFF, ..] => Ok(()), // eg. receiver feedback
+                _ if is_data => {
+                    if let Some(tx) = &*self.sender.lock().unwrap() {
+                        for message in Self::parse_midi(packet) {
+                            // if nothing is reading this session, drop messages rather than queueing them forever
+                            let _ = tx.try_send(message);
+                        }
+                    }
+                    Ok(())
+                },
+                _ => Ok(())
+            };
+            if let Err(e) = result {
+                println!("RTP-MIDI error: {}", e);
+            }
+        }
+    }
+
+    /// Accept an invitation, which is sent first to the control port then the data port
+    fn accept(&self, socket: &UdpSocket, from: SocketAddr, packet: &[u8], is_data: bool) -> Result<(), Box<dyn Error>> {
+        if packet.len() < 16 {
+            return Ok(());
+        }
+        let token = &packet[8..12];
+        let ssrc = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+        let mut reply = vec![0xFF, 0xFF, b'O', b'K'];
+        reply.extend(PROTOCOL_VERSION.to_be_bytes());
+        reply.extend(token);
+        reply.extend(self.ssrc.to_be_bytes());
+        reply.extend(SESSION_NAME.as_bytes());
+        reply.push(0);
+        if is_data {
+            // connected before replying, so the peer receives MIDI sent as soon as it has been accepted
+            let name = String::from_utf8_lossy(&packet[16..]).trim_end_matches('\0').to_string();
+            println!("RTP-MIDI session connected: {} ({})", name, from);
+            *self.peer.lock().unwrap() = Some(Peer {
+                ssrc,
+                data: from
+            });
+        }
+        socket.send_to(&reply, from)?;
+        Ok(())
+    }
+
+    /// Reply to the initiator's clock synchronization, which it uses to work out the latency
+    fn synchronize(&self, socket: &UdpSocket, from: SocketAddr, packet: &[u8]) -> Result<(), Box<dyn Error>> {
+        if packet.len() < 36 || packet[8] != 0 {
+            return Ok(());
+        }
+        let mut reply = packet[0..36].to_vec();
+        reply[4..8].copy_from_slice(&self.ssrc.to_be_bytes());
+        reply[8] = 1;
+        reply[20..28].copy_from_slice(&(self.timestamp() as u64).to_be_bytes());
+        socket.send_to(&reply, from)?;
+        Ok(())
+    }
+
+    fn end(&self, packet: &[u8]) {
+        if packet.len() < 16 {
+            return;
+        }
+        let ssrc = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+        let mut peer = self.peer.lock().unwrap();
+        if peer.is_some_and(|p| p.ssrc == ssrc) {
+            println!("RTP-MIDI session disconnected");
+            *peer = None;
+        }
+    }
+
+    /// The MIDI messages in an RTP-MIDI packet, ignoring delta times and the recovery journal
+    fn parse_midi(packet: &[u8]) -> Vec<MidiMessage<'static>> {
+        let mut messages = Vec::new();
+        if packet.len() < 13 || packet[0] & 0xC0 != RTP_VERSION || packet[1] & 0x7F != MIDI_PAYLOAD_TYPE {
+            return messages;
+        }
+        let flags = packet[12];
+        let (length, mut i) = if flags & 0x80 != 0 {
+            if packet.len() < 14 {
+                return messages;
+            }
+            ((((flags & 0x0F) as usize) << 8) | packet[13] as usize, 14)
+        } else {
+            ((flags & 0x0F) as usize, 13)
+        };
+        let end = (i + length).min(packet.len());
+        let mut has_delta_time = flags & 0x20 != 0;
+        let mut running_status = None;
+        while i < end {
+            if has_delta_time {
+                while i < end && packet[i] & 0x80 != 0 {
+                    i += 1;
+                }
+                i += 1;
+                if i >= end {
+                    break;
+                }
+            }
+            has_delta_time = true;
+            let status = if packet[i] & 0x80 != 0 {
+                i += 1;
+                packet[i - 1]
+            } else if let Some(status) = running_status {
+                status
+            } else {
+                break;
+            };
+            let data_length = match status {
+                0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+                0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+                0xF0 => packet[i..end].iter().position(|b| *b == 0xF7).map_or(end - i, |p| p + 1),
+                _ => 0
+            };
+            match status {
+                0x80..=0xEF => running_status = Some(status),
+                0xF0..=0xF7 => running_status = None,
+                _ => {} // real-time messages don't affect running status
+            }
+            let data_end = (i + data_length).min(end);
+            let mut bytes = vec![status];
+            bytes.extend(&packet[i..data_end]);
+            i = data_end;
+            if let Ok(message) = MidiMessage::try_from(bytes.as_slice()) {
+                messages.push(message.to_owned());
+            }
+        }
+        messages
+    }
+
+    /// The session time in units of 100 microseconds
+    fn timestamp(&self) -> u32 {
+        (self.start.elapsed().as_micros() / 100) as u32
+    }
+
+    fn random_ssrc() -> u32 {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        nanos ^ std::process::id().rotate_left(16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use wmidi::{Channel, Note, U7};
+
+    const PORT: u16 = 45004;
+    const PEER_SSRC: u32 = 0x12345678;
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn peer_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        socket
+    }
+
+    fn exchange(socket: &UdpSocket, packet: &[u8], port: u16) -> Vec<u8> {
+        socket.send_to(packet, ("127.0.0.1", port)).unwrap();
+        let mut buf = [0; MAX_PACKET];
+        let (length, _) = socket.recv_from(&mut buf).unwrap();
+        buf[0..length].to_vec()
+    }
+
+    fn invite(socket: &UdpSocket, port: u16) {
+        let mut invitation = vec![0xFF, 0xFF, b'I', b'N'];
+        invitation.extend(PROTOCOL_VERSION.to_be_bytes());
+        invitation.extend(0xCAFEu32.to_be_bytes()); // token
+        invitation.extend(PEER_SSRC.to_be_bytes());
+        invitation.extend(b"peer\0");
+        let reply = exchange(socket, &invitation, port);
+        assert_eq!(&reply[0..4], &[0xFF, 0xFF, b'O', b'K']);
+        assert_eq!(&reply[8..12], &0xCAFEu32.to_be_bytes());
+    }
+
+    #[test]
+    fn session_with_local_peer() {
+        let mut source = RtpSource::open(&PORT.to_string()).unwrap();
+        let mut sink = RtpSink::open(&PORT.to_string()).unwrap();
+        let control = peer_socket();
+        let data = peer_socket();
+        invite(&control, PORT);
+        invite(&data, PORT + 1);
+
+        let mut sync = vec![0xFF, 0xFF, b'C', b'K'];
+        sync.extend(PEER_SSRC.to_be_bytes());
+        sync.extend([0, 0, 0, 0]); // count 0 and padding
+        sync.extend(111u64.to_be_bytes());
+        sync.extend([0; 16]);
+        let reply = exchange(&data, &sync, PORT + 1);
+        assert_eq!(reply[8], 1);
+        assert_eq!(&reply[12..20], &111u64.to_be_bytes());
+
+        // a note on, then a note on using running status after a delta time, then a note off after a 2 byte delta time
+        let midi = [0x90, 0x3C, 0x40, 0x00, 0x3E, 0x41, 0x81, 0x00, 0x80, 0x3C, 0x00];
+        let mut packet = vec![RTP_VERSION, MIDI_PAYLOAD_TYPE, 0, 1];
+        packet.extend(0u32.to_be_bytes());
+        packet.extend(PEER_SSRC.to_be_bytes());
+        packet.push(midi.len() as u8);
+        packet.extend(midi);
+        data.send_to(&packet, ("127.0.0.1", PORT + 1)).unwrap();
+        assert_eq!(source.receiver.recv_timeout(TIMEOUT).unwrap(), MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(0x40)));
+        assert_eq!(source.receiver.recv_timeout(TIMEOUT).unwrap(), MidiMessage::NoteOn(Channel::Ch1, Note::D4, U7::from_u8_lossy(0x41)));
+        assert_eq!(source.receiver.recv_timeout(TIMEOUT).unwrap(), MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN));
+
+        sink.send(&MidiMessage::NoteOn(Channel::Ch2, Note::E4, U7::from_u8_lossy(0x50))).unwrap();
+        let mut buf = [0; MAX_PACKET];
+        let (length, _) = data.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[0..2], &[RTP_VERSION, MIDI_PAYLOAD_TYPE]);
+        assert_eq!(&buf[12..length], &[0x03, 0x91, 0x40, 0x50]);
+
+        // the session can be read again once the arpeggiator restarts
+        let reopened = RtpSource::open(&PORT.to_string()).unwrap();
+        assert_eq!(source.receive(), None);
+        data.send_to(&packet, ("127.0.0.1", PORT + 1)).unwrap();
+        assert_eq!(reopened.receiver.recv_timeout(TIMEOUT).unwrap(), MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(0x40)));
+    }
+
+    #[test]
+    fn last_port_is_rejected() {
+        assert!(RtpSource::open("65535").is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, Visitor};
+use serde_derive::Serialize;
 use wmidi::Note;
 
 impl Into<Note> for NoteName {
@@ -13,7 +17,110 @@ impl Into<Note> for &NoteName {
     }
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug)]
+impl NoteName {
+    const ALL: [NoteName; 128] = [
+        Self::CMinus1, Self::DbMinus1, Self::DMinus1, Self::EbMinus1, Self::EMinus1, Self::FMinus1, Self::GbMinus1, Self::GMinus1, Self::AbMinus1, Self::AMinus1, Self::BbMinus1, Self::BMinus1,
+        Self::C0, Self::Db0, Self::D0, Self::Eb0, Self::E0, Self::F0, Self::Gb0, Self::G0, Self::Ab0, Self::A0, Self::Bb0, Self::B0,
+        Self::C1, Self::Db1, Self::D1, Self::Eb1, Self::E1, Self::F1, Self::Gb1, Self::G1, Self::Ab1, Self::A1, Self::Bb1, Self::B1,
+        Self::C2, Self::Db2, Self::D2, Self::Eb2, Self::E2, Self::F2, Self::Gb2, Self::G2, Self::Ab2, Self::A2, Self::Bb2, Self::B2,
+        Self::C3, Self::Db3, Self::D3, Self::Eb3, Self::E3, Self::F3, Self::Gb3, Self::G3, Self::Ab3, Self::A3, Self::Bb3, Self::B3,
+        Self::C4, Self::Db4, Self::D4, Self::Eb4, Self::E4, Self::F4, Self::Gb4, Self::G4, Self::Ab4, Self::A4, Self::Bb4, Self::B4,
+        Self::C5, Self::Db5, Self::D5, Self::Eb5, Self::E5, Self::F5, Self::Gb5, Self::G5, Self::Ab5, Self::A5, Self::Bb5, Self::B5,
+        Self::C6, Self::Db6, Self::D6, Self::Eb6, Self::E6, Self::F6, Self::Gb6, Self::G6, Self::Ab6, Self::A6, Self::Bb6, Self::B6,
+        Self::C7, Self::Db7, Self::D7, Self::Eb7, Self::E7, Self::F7, Self::Gb7, Self::G7, Self::Ab7, Self::A7, Self::Bb7, Self::B7,
+        Self::C8, Self::Db8, Self::D8, Self::Eb8, Self::E8, Self::F8, Self::Gb8, Self::G8, Self::Ab8, Self::A8, Self::Bb8, Self::B8,
+        Self::C9, Self::Db9, Self::D9, Self::Eb9, Self::E9, Self::F9, Self::Gb9, Self::G9
+    ];
+
+    pub fn from_note(note: Note) -> Self {
+        Self::ALL[note as u8 as usize]
+    }
+
+    pub fn from_u8(number: u8) -> Option<Self> {
+        Self::ALL.get(number as usize).cloned()
+    }
+}
+
+impl FromStr for NoteName {
+    type Err = String;
+
+    /// Parses note names with flats or sharps in any case (eg. "Db4", "c#4", "Cb4", "B#3"), octave -1 as either "C-1" or "CMinus1", or MIDI note numbers (eg. "60")
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if let Ok(number) = text.parse::<u8>() {
+            return Self::from_u8(number).ok_or(format!("MIDI note number {} is out of range 0-127", number));
+        }
+        let mut chars = text.chars();
+        let natural: isize = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(format!("Invalid note name '{}'", text))
+        };
+        let mut rest = chars.as_str();
+        let accidental = if let Some(after) = rest.strip_prefix('#') {
+            rest = after;
+            1
+        } else if let Some(after) = rest.strip_prefix(|c| c == 'b' || c == 'B') {
+            rest = after;
+            -1
+        } else {
+            0
+        };
+        let octave: isize = if rest.eq_ignore_ascii_case("Minus1") {
+            -1
+        } else {
+            rest.parse().map_err(|_| format!("Invalid octave in note name '{}'", text))?
+        };
+        let number = (octave + 1) * 12 + natural + accidental;
+        if number >= 0 && number <= 127 {
+            Ok(Self::ALL[number as usize])
+        } else {
+            Err(format!("Note name '{}' is out of the MIDI note range", text))
+        }
+    }
+}
+
+struct NoteNameVisitor;
+
+impl<'de> Visitor<'de> for NoteNameVisitor {
+    type Value = NoteName;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a note name (eg. \"C#4\") or MIDI note number (0-127)")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+        text.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, number: u64) -> Result<Self::Value, E> {
+        if number <= 127 {
+            Ok(NoteName::ALL[number as usize])
+        } else {
+            Err(E::custom(format!("MIDI note number {} is out of range 0-127", number)))
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, number: i64) -> Result<Self::Value, E> {
+        if number >= 0 {
+            self.visit_u64(number as u64)
+        } else {
+            Err(E::custom(format!("MIDI note number {} is out of range 0-127", number)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NoteName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NoteNameVisitor)
+    }
+}
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Debug)]
 pub enum NoteName {
     CMinus1 = 0,
     DbMinus1 = 1,
@@ -146,3 +253,70 @@ pub enum NoteName {
     Gb9 = 126,
     G9 = 127,
 }
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Debug)]
+pub enum PitchClass {
+    C = 0,
+    Db = 1,
+    D = 2,
+    Eb = 3,
+    E = 4,
+    F = 5,
+    Gb = 6,
+    G = 7,
+    Ab = 8,
+    A = 9,
+    Bb = 10,
+    B = 11
+}
+
+impl PitchClass {
+    const ALL: [PitchClass; 12] = [Self::C, Self::Db, Self::D, Self::Eb, Self::E, Self::F, Self::Gb, Self::G, Self::Ab, Self::A, Self::Bb, Self::B];
+
+    pub fn from_semitones(semitones: u8) -> Self {
+        Self::ALL[semitones as usize % 12]
+    }
+
+    pub fn semitones(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Parse a pitch class (eg. "C", "F#", "Bb") from the start of the text, returning the remaining text
+    pub fn parse_prefix(text: &str) -> Option<(Self, &str)> {
+        let mut chars = text.chars();
+        let natural: u8 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None
+        };
+        let rest = chars.as_str();
+        if let Some(after) = rest.strip_prefix('#') {
+            Some((Self::from_semitones(natural + 1), after))
+        } else if let Some(after) = rest.strip_prefix('b') {
+            Some((Self::from_semitones(natural + 11), after))
+        } else {
+            Some((Self::from_semitones(natural), rest))
+        }
+    }
+}
+
+impl fmt::Display for PitchClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PitchClass {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        match Self::parse_prefix(&text) {
+            Some((pitch_class, "")) => Ok(pitch_class),
+            _ => Err(de::Error::custom(format!("Invalid pitch class '{}'", text)))
+        }
+    }
+}
\ No newline at end of file
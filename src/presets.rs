@@ -1,18 +1,447 @@
-use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
 
-use wmidi::Note;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+use wmidi::{Channel, Note};
 
+use crate::chord::{Chord, ChordQuality, Voicing};
+use crate::midi;
 use crate::notename::NoteName;
+use crate::smf::{FileNote, MidiFile};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Preset {
-    pub trigger: Vec<NoteName>,
-    pub steps: Vec<NoteName>,
-    pub ticks_per_step: Option<usize>
+    pub trigger: Vec<NoteSymbol>,
+    #[serde(default)]
+    pub steps: Vec<PresetStep>,
+    pub midi_file: Option<MidiFileSteps>,
+    pub ticks_per_step: Option<usize>,
+    pub trigger_quality: Option<ChordQuality>,
+    #[serde(default)]
+    pub transpose: bool,
+    pub match_mode: Option<MatchMode>,
+    pub priority: Option<i32>
+}
+
+/// How the held notes are compared to the trigger notes of a preset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchMode {
+    Subset, // all trigger notes are held, along with any other notes (default)
+    Exact, // only the trigger notes are held
+    Sequence, // all trigger notes are held, and were pressed in the order listed
+    LowestNote // the lowest held note is the lowest trigger note
 }
 
 impl Preset {
-    pub fn is_triggered_by(&self, notes: &HashSet<Note>) -> bool {
-        self.trigger.iter().all(|n| notes.contains(&n.into()))
+    /// The number of semitones to transpose the steps by when triggered by these notes (in the order they were pressed), or None if not triggered.
+    /// This is always 0 unless the preset is transposable, in which case the trigger notes can be held in any key.
+    pub fn trigger_offset(&self, notes: &[Note]) -> Option<i8> {
+        let lowest: Note = match self.trigger_notes().iter().min() {
+            Some(lowest) if self.transpose => lowest.into(),
+            _ => return Some(0).filter(|o| self.is_triggered_at(notes, *o))
+        };
+        let mut held: Vec<&Note> = notes.iter().collect();
+        held.sort();
+        held.into_iter()
+            .map(|n| u8::from(*n) as i8 - u8::from(lowest) as i8)
+            .find(|offset| self.is_triggered_at(notes, *offset))
+    }
+
+    pub fn is_triggered_at(&self, notes: &[Note], offset: i8) -> bool {
+        if let Some(quality) = self.trigger_quality {
+            match Chord::recognise(notes) {
+                Some(chord) if chord.quality == quality => {},
+                _ => return false
+            }
+        }
+        let mut trigger = Vec::new();
+        for n in self.trigger_notes() {
+            let note: Note = n.into();
+            match note.step(offset) {
+                Ok(transposed) => trigger.push(transposed),
+                Err(_) => return false
+            }
+        }
+        if trigger.len() == 0 {
+            return true;
+        }
+        match self.match_mode.unwrap_or(MatchMode::Subset) {
+            MatchMode::Subset => trigger.iter().all(|t| notes.contains(t)),
+            MatchMode::Exact => trigger.iter().all(|t| notes.contains(t)) && notes.iter().all(|n| trigger.contains(n)),
+            MatchMode::Sequence => notes.iter().filter(|n| trigger.contains(n)).eq(trigger.iter()),
+            MatchMode::LowestNote => notes.iter().min() == trigger.iter().min()
+        }
+    }
+
+    /// Used to choose between presets triggered at the same time, the highest priority (default 0) wins, then the preset with the most trigger notes
+    fn precedence(&self) -> (i32, usize) {
+        let specificity = self.trigger_notes().len() + if self.trigger_quality.is_some() { 1 } else { 0 };
+        (self.priority.unwrap_or(0), specificity)
+    }
+
+    /// Replace the steps with those imported from the preset's MIDI file (if set), relative to the directory of the settings file
+    pub fn import_steps(&mut self, directory: &Path) -> Result<(), String> {
+        if let Some(midi_file) = &self.midi_file {
+            if self.steps.len() > 0 {
+                return Err(format!("Preset cannot have both steps and a midi_file"));
+            }
+            self.steps = midi_file.import(directory)?;
+        }
+        Ok(())
+    }
+
+    pub fn trigger_notes(&self) -> Vec<NoteName> {
+        self.trigger.iter().flat_map(|s| s.notes().iter().cloned()).collect()
+    }
+
+    pub fn step_notes(&self) -> Vec<NoteName> {
+        self.steps.iter().flat_map(|s| s.notes()).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.trigger.len() == 0 && self.trigger_quality.is_none() {
+            Err(format!("Preset must have trigger notes or a trigger_quality"))
+        } else if self.steps.len() == 0 {
+            Err(format!("Preset must have at least 1 step"))
+        } else if self.transpose && self.trigger.len() == 0 {
+            Err(format!("A transposable preset must have trigger notes"))
+        } else if self.match_mode.is_some() && self.trigger.len() == 0 {
+            Err(format!("A preset with a match_mode must have trigger notes"))
+        } else if self.ticks_per_step == Some(0) {
+            Err(format!("Preset ticks_per_step must be at least 1"))
+        } else {
+            for (i, step) in self.steps.iter().enumerate() {
+                if let PresetStep::Detailed(details) = step {
+                    details.validate().map_err(|e| format!("Invalid step #{} ({}): {}", i + 1, step, e))?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trigger: [")?;
+        for i in 0..self.trigger.len() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.trigger[i])?;
+        }
+        write!(f, "], steps: [")?;
+        for i in 0..self.steps.len() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.steps[i])?;
+        }
+        write!(f, "]")?;
+        if let Some(mode) = self.match_mode {
+            write!(f, " ({:?})", mode)?;
+        }
+        if self.transpose {
+            write!(f, " (transposable)")?;
+        }
+        if let Some(priority) = self.priority {
+            write!(f, " (priority {})", priority)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the preset triggered by these notes (in the order they were pressed) which takes precedence, and the semitones to transpose it by.
+/// When presets have equal precedence, the first one wins.
+pub fn find_preset(presets: &[Preset], notes: &[Note]) -> Option<(usize, i8)> {
+    let mut found: Option<(usize, i8)> = None;
+    for i in 0..presets.len() {
+        if let Some(offset) = presets[i].trigger_offset(notes) {
+            match found {
+                Some((f, _)) if presets[f].precedence() >= presets[i].precedence() => {},
+                _ => found = Some((i, offset))
+            }
+        }
+    }
+    found
+}
+
+/// Warnings for presets which can never be found, because another preset takes precedence whenever they are triggered
+pub fn shadowed_presets(presets: &[Preset]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for i in 0..presets.len() {
+        let notes: Vec<Note> = presets[i].trigger_notes().iter().map(|n| n.into()).collect();
+        if presets[i].trigger_offset(&notes).is_none() {
+            continue; // can only be triggered by other notes, so it cannot be checked
+        }
+        if let Some((found, _)) = find_preset(presets, &notes) {
+            if found != i {
+                warnings.push(format!("Preset #{} ({}) is shadowed by preset #{} ({}) when its trigger notes are held", i + 1, presets[i], found + 1, presets[found]));
+            }
+        }
+    }
+    warnings
+}
+
+/// Steps imported from the notes of a Standard MIDI File, rather than listed in the preset.
+/// Notes starting at the same time are played as one step, lasting until the next step (or rest) in MIDI clock ticks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MidiFileSteps {
+    pub path: String,
+    pub track: Option<usize>, // from 0, defaults to all tracks
+    pub channel: Option<u8>, // 1-16, defaults to all channels
+    pub start_bar: Option<u32>, // from 1, defaults to the first bar
+    pub end_bar: Option<u32> // inclusive, defaults to the last note
+}
+
+impl MidiFileSteps {
+    fn import(&self, directory: &Path) -> Result<Vec<PresetStep>, String> {
+        let channel = match self.channel {
+            Some(c) if c >= 1 && c <= 16 => Some(Channel::from_index(c - 1).unwrap()),
+            Some(c) => return Err(format!("Invalid midi_file channel {} (expected 1-16)", c)),
+            None => None
+        };
+        if self.start_bar == Some(0) || self.end_bar == Some(0) {
+            return Err(format!("midi_file bars start from 1"));
+        }
+        let file = MidiFile::read(&directory.join(&self.path))?;
+        if let Some(track) = self.track {
+            if track >= file.tracks.len() {
+                return Err(format!("'{}' has no track {} (it has {} tracks)", self.path, track, file.tracks.len()));
+            }
+        }
+        let ticks_per_bar = file.ticks_per_bar();
+        let bar_ticks = |bar: u32| bar.checked_mul(ticks_per_bar).ok_or(format!("midi_file bar {} is beyond the end of any MIDI file", bar));
+        let start = bar_ticks(self.start_bar.unwrap_or(1) - 1)?;
+        let end = self.end_bar.map(bar_ticks).transpose()?;
+        let notes: Vec<FileNote> = file.notes(self.track, channel).into_iter()
+            .filter(|n| n.start >= start && end.map(|e| n.start < e).unwrap_or(true))
+            .collect();
+        let steps = file.steps(notes, start, end, midi::TICKS_PER_BEAT as u32).into_iter()
+            .map(|step| PresetStep::Detailed(StepDefinition {
+                notes: step.notes.iter().map(|n| NoteSymbol::from_name(NoteName::from_note(n.note))).collect(),
+                velocity: step.notes.iter().map(|n| u8::from(n.velocity)).max(),
+                ticks: Some(step.ticks as usize),
+                tie: false
+            }))
+            .collect();
+        Ok(steps)
+    }
+}
+
+/// A step of a preset, either a note or chord symbol (eg. "C4", "Dm7"), or an object which can specify the step's velocity and length in ticks, or be a rest or tie
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum PresetStep {
+    Symbol(NoteSymbol),
+    Detailed(StepDefinition)
+}
+
+impl PresetStep {
+    const REST: &'static str = "rest";
+    const TIE: &'static str = "tie";
+
+    pub fn notes(&self) -> Vec<NoteName> {
+        match self {
+            Self::Symbol(symbol) => symbol.notes().to_vec(),
+            Self::Detailed(details) => details.notes.iter().flat_map(|s| s.notes().iter().cloned()).collect()
+        }
+    }
+}
+
+impl fmt::Display for PresetStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Symbol(symbol) => write!(f, "{}", symbol),
+            Self::Detailed(details) => write!(f, "{}", details)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PresetStep {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PresetStepVisitor)
+    }
+}
+
+struct PresetStepVisitor;
+
+impl<'de> de::Visitor<'de> for PresetStepVisitor {
+    type Value = PresetStep;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a note, chord symbol, \"rest\", \"tie\" or step object")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        if value.eq_ignore_ascii_case(PresetStep::REST) {
+            Ok(PresetStep::Detailed(StepDefinition::default()))
+        } else if value.eq_ignore_ascii_case(PresetStep::TIE) {
+            Ok(PresetStep::Detailed(StepDefinition { tie: true, ..Default::default() }))
+        } else {
+            NoteSymbolVisitor.visit_str(value).map(PresetStep::Symbol)
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        NoteSymbolVisitor.visit_u64(value).map(PresetStep::Symbol)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        NoteSymbolVisitor.visit_i64(value).map(PresetStep::Symbol)
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        StepDefinition::deserialize(de::value::MapAccessDeserializer::new(map)).map(PresetStep::Detailed)
+    }
+}
+
+/// A single step of a preset, with no notes being a rest
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct StepDefinition {
+    #[serde(default)]
+    pub notes: Vec<NoteSymbol>,
+    pub velocity: Option<u8>,
+    pub ticks: Option<usize>,
+    #[serde(default)]
+    pub tie: bool
+}
+
+impl StepDefinition {
+    fn validate(&self) -> Result<(), String> {
+        if self.tie && self.notes.len() > 0 {
+            Err(format!("A tie cannot have notes"))
+        } else if self.ticks == Some(0) {
+            Err(format!("Step ticks must be at least 1"))
+        } else if self.velocity.map(|v| v > 127).unwrap_or(false) {
+            Err(format!("Step velocity must be between 0 and 127"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for StepDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tie {
+            write!(f, "{}", PresetStep::TIE)?;
+        } else if self.notes.len() == 0 {
+            write!(f, "{}", PresetStep::REST)?;
+        } else {
+            write!(f, "[")?;
+            for i in 0..self.notes.len() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", self.notes[i])?;
+            }
+            write!(f, "]")?;
+        }
+        if let Some(velocity) = self.velocity {
+            write!(f, " v{}", velocity)?;
+        }
+        if let Some(ticks) = self.ticks {
+            write!(f, " {}ticks", ticks)?;
+        }
+        Ok(())
+    }
+}
+
+/// A note name (eg. "C4") or a chord symbol with optional octave and voicing (eg. "Cmaj7/E", "Dm9@3", "G7@3 drop2"), which is expanded into notes when loaded.
+/// Anything which is a valid note name on its own (eg. "G7", "C5", "e6") is a note, so those chord symbols need an octave or voicing to be read as chords (eg. "G7@4", "C5 close").
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NoteSymbol {
+    symbol: String,
+    notes: Vec<NoteName>
+}
+
+impl NoteSymbol {
+    const DEFAULT_OCTAVE: i8 = 4;
+
+    pub fn parse(symbol: &str) -> Result<Self, String> {
+        // an octave, voicing or bass note always means a chord
+        let is_chord = symbol.contains(['@', ' ', '/']);
+        if !is_chord {
+            if let Ok(name) = symbol.parse::<NoteName>() {
+                return Ok(Self {
+                    symbol: symbol.to_string(),
+                    notes: vec![name]
+                });
+            }
+        }
+        let (chord_and_octave, voicing) = match symbol.split_once(' ') {
+            Some((c, v)) => (c, Voicing::parse(v.trim()).ok_or(format!("Unknown voicing '{}' in '{}' (expected close, drop2, drop3 or spread)", v, symbol))?),
+            None => (symbol, Voicing::Close)
+        };
+        let (chord_text, octave) = match chord_and_octave.split_once('@') {
+            Some((c, o)) => (c, o.parse::<i8>().map_err(|_| format!("Invalid octave '{}' in '{}'", o, symbol))?),
+            None => (chord_and_octave, Self::DEFAULT_OCTAVE)
+        };
+        let chord = Chord::parse(chord_text)?;
+        Ok(Self {
+            symbol: symbol.to_string(),
+            notes: chord.voice(octave, voicing)?.into_iter().map(NoteName::from_note).collect()
+        })
+    }
+
+    pub fn from_name(name: NoteName) -> Self {
+        Self {
+            symbol: format!("{:?}", name),
+            notes: vec![name]
+        }
+    }
+
+    pub fn notes(&self) -> &[NoteName] {
+        &self.notes
+    }
+}
+
+impl fmt::Display for NoteSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbol)?;
+        if self.notes.len() != 1 || format!("{:?}", self.notes[0]) != self.symbol {
+            write!(f, " = {:?}", self.notes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for NoteSymbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.symbol)
+    }
+}
+
+impl<'de> Deserialize<'de> for NoteSymbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NoteSymbolVisitor)
+    }
+}
+
+struct NoteSymbolVisitor;
+
+impl<'de> de::Visitor<'de> for NoteSymbolVisitor {
+    type Value = NoteSymbol;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a note name, MIDI note number (0-127) or chord symbol")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        NoteSymbol::parse(value).map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        if value > 127 {
+            return Err(E::custom(format!("MIDI note number {} is out of range 0-127", value)));
+        }
+        NoteSymbol::parse(&value.to_string()).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        if value < 0 {
+            return Err(E::custom(format!("MIDI note number {} is out of range 0-127", value)));
+        }
+        self.visit_u64(value as u64)
     }
 }
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::{fmt, fs};
+use std::path::Path;
+use serde::{Deserialize, Deserializer};
+use serde::de;
+
+use crate::devices::{self, MidiDevice};
+use crate::midi;
+use crate::midi::filter::OutputFilter;
+
+/// Which MIDI devices the arpeggiator reads notes from, gets its clock from, and sends its output to.
+/// Each device is anything accepted on the command line (eg. "/dev/midi1", "name:KeyStep", "usb:1c75:0288", "alsa:20:0", "rtp:5004", "fifo:/tmp/arp").
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Routing {
+    pub inputs: Vec<String>,
+    pub clock: Option<String>, // if not set, clock ticks are taken from the inputs
+    pub outputs: Vec<Output>
+}
+
+/// An output device, either on its own (eg. "/dev/midi2") to send it everything,
+/// or with a filter (eg. {"device": "/dev/midi2", "source": "Arpeggio", "channel": 2}) to send it only some messages
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Output {
+    Device(String),
+    Filtered {
+        device: String,
+        #[serde(flatten)]
+        filter: OutputFilter
+    }
+}
+
+impl<'de> Deserialize<'de> for Output {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(OutputVisitor)
+    }
+}
+
+struct OutputVisitor;
+
+impl<'de> de::Visitor<'de> for OutputVisitor {
+    type Value = Output;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a device or an output object")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(Output::Device(value.to_owned()))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        // the filter is read from the fields other than the device, rather than flattened, so that it can reject unknown fields
+        let mut fields = serde_json::Map::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        let device = match fields.remove("device") {
+            Some(serde_json::Value::String(device)) => device,
+            Some(other) => return Err(de::Error::invalid_type(de::Unexpected::Other(&other.to_string()), &"a device")),
+            None => return Err(de::Error::missing_field("device"))
+        };
+        let filter = OutputFilter::deserialize(serde_json::Value::Object(fields)).map_err(de::Error::custom)?;
+        Ok(Output::Filtered {
+            device,
+            filter
+        })
+    }
+}
+
+impl Output {
+    pub fn device(&self) -> &String {
+        match self {
+            Self::Device(device) | Self::Filtered { device, .. } => device
+        }
+    }
+
+    pub fn filter(&self) -> OutputFilter {
+        match self {
+            Self::Device(_) => OutputFilter::default(),
+            Self::Filtered { filter, .. } => filter.clone()
+        }
+    }
+
+    fn with_device(&self, device: String) -> Self {
+        match self {
+            Self::Device(_) => Self::Device(device),
+            Self::Filtered { filter, .. } => Self::Filtered { device, filter: filter.clone() }
+        }
+    }
+}
+
+impl Routing {
+    pub fn load(file: &str) -> Result<Self, Box<dyn Error>> {
+        let json = fs::read_to_string(file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+        let routing: Routing = serde_json::from_str(&json).map_err(|e| format!("Cannot parse routing from '{}': {}", file, e))?;
+        routing.validate().map_err(|e| format!("Invalid routing in '{}': {}", file, e))?;
+        Ok(routing)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.inputs.is_empty() {
+            return Err("At least 1 input is required".to_owned());
+        }
+        if self.outputs.is_empty() {
+            return Err("At least 1 output is required".to_owned());
+        }
+        for output in &self.outputs {
+            output.filter().validate().map_err(|e| format!("Output '{}': {}", output.device(), e))?;
+            if output.device().starts_with(midi::FIFO_PREFIX) && self.inputs.contains(output.device()) {
+                // everything written to a pipe would be read straight back from it
+                return Err(format!("'{}' cannot be both an input and an output, use a separate pipe for each", output.device()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The clock device, unless clock ticks are taken from the inputs
+    pub fn external_clock(&self) -> Option<&str> {
+        self.clock.as_deref().filter(|c| !self.inputs.iter().any(|i| i == c))
+    }
+
+    /// Devices which are both an input and an output shouldn't have notes passed back to them,
+    /// unless the output explicitly chooses which source of notes it gets
+    pub fn output_device_is_input_device(&self) -> bool {
+        self.outputs.iter().any(|o| self.inputs.contains(o.device()) && o.filter().source.is_none())
+    }
+
+    /// Each output device along with the filter for the messages sent to it
+    pub fn filtered_outputs(&self) -> Vec<(String, OutputFilter)> {
+        self.outputs.iter().map(|o| (o.device().clone(), o.filter())).collect()
+    }
+
+    /// The routing for a single MIDI-IN and MIDI-OUT device given on the command line, with clock-ticks from MIDI-OUT if it can send them
+    pub fn between(midi_in: &str, midi_out: &str) -> Self {
+        Self {
+            inputs: vec![midi_in.to_owned()],
+            clock: Some(midi_out.to_owned()).filter(|c| c != midi_in && midi::can_send_clock(c)),
+            outputs: vec![Output::Device(midi_out.to_owned())]
+        }
+    }
+
+    /// The routing with any device names/USB IDs replaced by the device files they match,
+    /// or the reasons why devices are missing
+    pub fn resolve(&self, devices: &[MidiDevice]) -> Result<Self, Vec<String>> {
+        let mut missing = Vec::new();
+        let mut resolve = |device: &String| {
+            let (path, reason) = match devices::resolve(device, devices) {
+                Ok(path) if path.starts_with('/') && !Path::new(&path).exists() => (path.clone(), Some(format!("'{}' is not connected", path))),
+                Ok(path) => (path, None),
+                Err(e) => (device.clone(), Some(e))
+            };
+            if let Some(reason) = reason.filter(|r| !missing.contains(r)) {
+                missing.push(reason);
+            }
+            path
+        };
+        let resolved = Self {
+            inputs: self.inputs.iter().map(&mut resolve).collect(),
+            clock: self.clock.as_ref().map(&mut resolve),
+            outputs: self.outputs.iter().map(|o| o.with_device(resolve(o.device()))).collect()
+        };
+        if missing.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(missing)
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use wmidi::Note;
+
+use crate::notename::PitchClass;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub root: PitchClass,
+    pub mode: ScaleMode
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ScaleMode {
+    Major,
+    Minor,
+    HarmonicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    Pentatonic,
+    MinorPentatonic,
+    Custom(Vec<u8>) // semitones above the root, eg. [0, 2, 4, 5, 7, 9, 11] is the same as Major
+}
+
+impl ScaleMode {
+    const CHROMATIC: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+    fn intervals(&self) -> &[u8] {
+        match self {
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Self::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Self::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Self::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Self::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Self::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Self::Pentatonic => &[0, 2, 4, 7, 9],
+            Self::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Self::Custom(intervals) => intervals
+        }
+    }
+
+    /// Distinct semitones within the octave, in ascending order
+    fn degrees(&self) -> Vec<u8> {
+        let mut degrees: Vec<u8> = self.intervals().iter().map(|i| i % 12).collect();
+        degrees.sort();
+        degrees.dedup();
+        if degrees.len() == 0 {
+            Self::CHROMATIC.to_vec()
+        } else {
+            degrees
+        }
+    }
+}
+
+impl Scale {
+    pub const C_MAJOR: Scale = Scale { root: PitchClass::C, mode: ScaleMode::Major };
+
+    /// Search distance when quantizing, which is only exceeded by a custom scale with no intervals
+    const MAX_DISTANCE: i8 = 6;
+
+    pub fn contains(&self, note: Note) -> bool {
+        let semitones_above_root = (note as u8 + 12 - self.root.semitones()) % 12;
+        self.mode.intervals().iter().any(|i| i % 12 == semitones_above_root)
+    }
+
+    /// Snap a note to the nearest note in the scale, preferring the lower note if equally near
+    pub fn quantize(&self, note: Note) -> Note {
+        if self.contains(note) {
+            return note;
+        }
+        for distance in 1..=Self::MAX_DISTANCE {
+            if let Ok(lower) = note.step(-distance) {
+                if self.contains(lower) {
+                    return lower;
+                }
+            }
+            if let Ok(higher) = note.step(distance) {
+                if self.contains(higher) {
+                    return higher;
+                }
+            }
+        }
+        note
+    }
+
+    /// Transpose a note by the number of scale degrees between `from` and `to` (rather than the number of semitones),
+    /// so that the transposed notes stay within the scale. Notes outside the scale keep their offset from the scale degree below.
+    pub fn transpose(&self, note: Note, from: Note, to: Note) -> Option<Note> {
+        let degrees = self.mode.degrees();
+        let (note_degree, note_offset) = self.degree_of(&degrees, note);
+        let (from_degree, from_offset) = self.degree_of(&degrees, from);
+        let (to_degree, to_offset) = self.degree_of(&degrees, to);
+        let transposed_degree = note_degree + to_degree - from_degree;
+        let transposed = self.semitones_of(&degrees, transposed_degree) + note_offset + to_offset - from_offset;
+        if transposed >= 0 && transposed <= 127 {
+            Some(Note::from_u8_lossy(transposed as u8))
+        } else {
+            None
+        }
+    }
+
+    /// Move a note up (or down if negative) by a number of scale degrees, keeping any offset from the scale
+    pub fn step(&self, note: Note, degrees: isize) -> Option<Note> {
+        let scale_degrees = self.mode.degrees();
+        let (note_degree, note_offset) = self.degree_of(&scale_degrees, note);
+        let stepped = self.semitones_of(&scale_degrees, note_degree + degrees) + note_offset;
+        if stepped >= 0 && stepped <= 127 {
+            Some(Note::from_u8_lossy(stepped as u8))
+        } else {
+            None
+        }
+    }
+
+    /// The absolute scale degree (counting from the root of octave -1) at or below this note, and the semitones above that degree
+    fn degree_of(&self, degrees: &[u8], note: Note) -> (isize, isize) {
+        let semitones_from_root = note as u8 as isize - self.root.semitones() as isize;
+        let octave = semitones_from_root.div_euclid(12);
+        let within_octave = semitones_from_root.rem_euclid(12);
+        if let Some(index) = degrees.iter().rposition(|d| *d as isize <= within_octave) {
+            (octave * degrees.len() as isize + index as isize, within_octave - degrees[index] as isize)
+        } else {
+            // below the first degree of a custom scale, therefore above the last degree of the previous octave
+            (octave * degrees.len() as isize - 1, within_octave + 12 - degrees[degrees.len() - 1] as isize)
+        }
+    }
+
+    fn semitones_of(&self, degrees: &[u8], degree: isize) -> isize {
+        let octave = degree.div_euclid(degrees.len() as isize);
+        let index = degree.rem_euclid(degrees.len() as isize) as usize;
+        self.root.semitones() as isize + octave * 12 + degrees[index] as isize
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
+use std::path::Path;
 use std::time::Instant;
 
 use wmidi::{MidiMessage, ControlFunction, U7, Channel};
@@ -8,7 +9,9 @@ use wmidi::{MidiMessage, ControlFunction, U7, Channel};
 use crate::arpeggio::{NoteDetails, Step};
 use crate::arpeggiator::{Pattern, ArpeggiatorMode};
 use crate::midi::{MidiReceiver, self};
-use crate::presets::Preset;
+use crate::harmony::Harmonizer;
+use crate::presets::{self, Preset};
+use crate::scale::Scale;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Settings {
@@ -20,7 +23,26 @@ pub struct Settings {
     pub pattern: Pattern,
     pub double_notes: Option<Vec<i8>>,
     pub presets: Option<Vec<Preset>>,
-    pub max_repeats: Option<usize>
+    pub max_repeats: Option<usize>,
+    pub velocity_layers: Option<Vec<VelocityLayer>>,
+    pub velocity_mode: Option<VelocityMode>,
+    pub scale: Option<Scale>,
+    pub harmonizer: Option<Harmonizer>,
+    pub export_directory: Option<String>,
+    pub recordings_directory: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VelocityLayer {
+    pub min_velocity: u8, // 0-127
+    #[serde(flatten)]
+    pub settings: Settings
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum VelocityMode {
+    Split, // each note is routed to the layer matching its own velocity
+    Switched // the first note played (while no notes are held) selects the layer for all notes until they are released
 }
 
 impl Settings {
@@ -34,7 +56,13 @@ impl Settings {
             pattern: Pattern::Up,
             double_notes: None,
             presets: None,
-            max_repeats: None
+            max_repeats: None,
+            velocity_layers: None,
+            velocity_mode: None,
+            scale: None,
+            harmonizer: None,
+            export_directory: None,
+            recordings_directory: None
         }
     }
 
@@ -59,6 +87,53 @@ impl Settings {
         }
     }
 
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(presets) = &self.presets {
+            for i in 0..presets.len() {
+                presets[i].validate().map_err(|e| format!("Invalid preset #{} ({}): {}", i + 1, presets[i], e))?;
+            }
+        }
+        if let Some(layers) = &self.velocity_layers {
+            for layer in layers {
+                if layer.settings.velocity_layers.is_some() {
+                    // only the top level settings are split into layers
+                    return Err(format!("Invalid velocity layer (min_velocity {}): velocity layers cannot have velocity_layers of their own", layer.min_velocity));
+                }
+                layer.settings.validate().map_err(|e| format!("Invalid velocity layer (min_velocity {}): {}", layer.min_velocity, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Import any preset steps from MIDI files, relative to the directory of the settings file
+    pub fn import_midi_files(&mut self, directory: &Path) -> Result<(), String> {
+        if let Some(presets) = &mut self.presets {
+            for i in 0..presets.len() {
+                presets[i].import_steps(directory).map_err(|e| format!("Cannot import steps for preset #{}: {}", i + 1, e))?;
+            }
+        }
+        if let Some(layers) = &mut self.velocity_layers {
+            for layer in layers {
+                layer.settings.import_midi_files(directory).map_err(|e| format!("Velocity layer (min_velocity {}): {}", layer.min_velocity, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Problems with the settings which do not prevent them being used
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let (Some(presets), ArpeggiatorMode::PrerecordedSets) = (&self.presets, &self.mode) {
+            warnings.extend(presets::shadowed_presets(presets));
+        }
+        if let Some(layers) = &self.velocity_layers {
+            for layer in layers {
+                warnings.extend(layer.settings.warnings().into_iter().map(|w| format!("Velocity layer (min_velocity {}): {}", layer.min_velocity, w)));
+            }
+        }
+        warnings
+    }
+
     pub fn _load(file: String) -> Result<Vec<Self>, Box<dyn Error>> {
         let json = fs::read_to_string(&file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
         let settings: Vec<Settings> = serde_json::from_str(&format!("[{}]", json)).map_err(|e| format!("Cannot parse settigs from '{}': {}", file, e))?;
@@ -134,7 +209,15 @@ pub struct SettingsWithProgramInfo {
 impl SettingsWithProgramInfo {
     pub fn load(file: String) -> Result<Vec<Self>, Box<dyn Error>> {
         let json = fs::read_to_string(&file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
-        let settings: Vec<SettingsWithProgramInfo> = serde_json::from_str(&format!("[{}]", json)).map_err(|e| format!("Cannot parse settigs from '{}': {}", file, e))?;
+        let mut settings: Vec<SettingsWithProgramInfo> = serde_json::from_str(&format!("[{}]", json)).map_err(|e| format!("Cannot parse settigs from '{}': {}", file, e))?;
+        let directory = Path::new(&file).parent().unwrap_or(Path::new("."));
+        for s in &mut settings {
+            s.settings.import_midi_files(directory).map_err(|e| format!("Invalid settings for msb {}, lsb {}, pc {} in '{}': {}", s.msb, s.lsb, s.pc, file, e))?;
+            s.settings.validate().map_err(|e| format!("Invalid settings for msb {}, lsb {}, pc {} in '{}': {}", s.msb, s.lsb, s.pc, file, e))?;
+            for warning in s.settings.warnings() {
+                println!("Warning for msb {}, lsb {}, pc {} in '{}': {}", s.msb, s.lsb, s.pc, file, warning);
+            }
+        }
         Ok(settings)
     }
 }
@@ -205,7 +288,7 @@ pub struct BpmDetector {
 }
 
 impl BpmDetector {
-    pub fn _new() -> Self {
+    pub fn new() -> Self {
         Self {
             ticks: 0,
             last_beat: Instant::now(),
@@ -213,7 +296,7 @@ impl BpmDetector {
         }
     }
 
-    pub fn _get(&self) -> usize {
+    pub fn get(&self) -> usize {
         self.last_bpm
     }
 }
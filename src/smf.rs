@@ -0,0 +1,361 @@
+use std::fs;
+use std::path::Path;
+
+use wmidi::{Channel, MidiMessage, Note, Velocity};
+
+/// A Standard MIDI File, with the events of each track at their absolute time in ticks
+pub struct MidiFile {
+    pub ticks_per_beat: u16,
+    pub tracks: Vec<Vec<(u32, Event)>>
+}
+
+pub enum Event {
+    Midi(MidiMessage<'static>),
+    Tempo(u32), // microseconds per beat
+    TimeSignature(u8, u8), // numerator, denominator
+    Marker(String),
+    EndOfTrack
+}
+
+/// A note read from a MIDI file, with its start and end in ticks
+#[derive(Clone)]
+pub struct FileNote {
+    pub start: u32,
+    pub end: u32,
+    pub channel: Channel,
+    pub note: Note,
+    pub velocity: Velocity
+}
+
+/// Notes starting at the same time, with the ticks until the next step starts (or no notes for a rest)
+pub struct FileStep {
+    pub notes: Vec<FileNote>,
+    pub ticks: u32
+}
+
+impl MidiFile {
+    const HEADER: &'static [u8] = b"MThd";
+    const TRACK: &'static [u8] = b"MTrk";
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Cannot read from '{}': {}", path.display(), e))?;
+        Self::parse(&bytes).map_err(|e| format!("Cannot parse MIDI file '{}': {}", path.display(), e))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4)? != Self::HEADER {
+            return Err(format!("Missing header"));
+        }
+        let header = reader.chunk()?;
+        if header.len() < 6 {
+            return Err(format!("Header too short"));
+        }
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if division & 0x8000 != 0 {
+            return Err(format!("SMPTE timing is not supported"));
+        }
+        if division == 0 {
+            return Err("Invalid division".to_owned());
+        }
+        let mut tracks = Vec::new();
+        while !reader.is_empty() {
+            let chunk_type = reader.take(4)?;
+            let chunk = reader.chunk()?;
+            if chunk_type == Self::TRACK {
+                tracks.push(Self::parse_track(chunk).map_err(|e| format!("Track {}: {}", tracks.len(), e))?);
+            }
+        }
+        Ok(Self {
+            ticks_per_beat: division,
+            tracks
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| format!("Cannot write to '{}': {}", path.display(), e))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(Self::HEADER);
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        let format: u16 = if self.tracks.len() == 1 { 0 } else { 1 };
+        bytes.extend_from_slice(&format.to_be_bytes());
+        bytes.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.ticks_per_beat.to_be_bytes());
+        for track in &self.tracks {
+            let data = Self::track_bytes(track);
+            bytes.extend_from_slice(Self::TRACK);
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            bytes.extend(data);
+        }
+        bytes
+    }
+
+    fn track_bytes(events: &[(u32, Event)]) -> Vec<u8> {
+        let mut sorted: Vec<&(u32, Event)> = events.iter().collect();
+        // notes on are sorted after other events at the same time, so repeated notes are not cut short
+        sorted.sort_by_key(|(ticks, event)| (*ticks, match event {
+            Event::Midi(MidiMessage::NoteOn(..)) => 1,
+            Event::EndOfTrack => 2,
+            _ => 0
+        }));
+        let mut bytes = Vec::new();
+        let mut last_ticks = 0;
+        for (ticks, event) in sorted {
+            write_var_len(&mut bytes, ticks - last_ticks);
+            last_ticks = *ticks;
+            match event {
+                Event::Midi(message) => bytes.extend(message.to_vec()),
+                Event::Tempo(micros) => {
+                    bytes.extend_from_slice(&[0xFF, 0x51, 3]);
+                    bytes.extend_from_slice(&micros.to_be_bytes()[1..]);
+                },
+                Event::TimeSignature(numerator, denominator) => {
+                    bytes.extend_from_slice(&[0xFF, 0x58, 4, *numerator, denominator.trailing_zeros() as u8, 24, 8]);
+                },
+                Event::Marker(text) => {
+                    bytes.extend_from_slice(&[0xFF, 0x06]);
+                    write_var_len(&mut bytes, text.len() as u32);
+                    bytes.extend_from_slice(text.as_bytes());
+                },
+                Event::EndOfTrack => {
+                    bytes.extend_from_slice(&[0xFF, 0x2F, 0]);
+                    return bytes;
+                }
+            }
+        }
+        // tracks must end with an end of track event
+        bytes.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+        bytes
+    }
+
+    fn parse_track(bytes: &[u8]) -> Result<Vec<(u32, Event)>, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        let mut events = Vec::new();
+        let mut ticks: u32 = 0;
+        let mut running_status = None;
+        while !reader.is_empty() {
+            ticks = ticks.checked_add(reader.var_len()?).ok_or(format!("Track too long after tick {}", ticks))?;
+            let mut status = reader.u8()?;
+            match status {
+                0xFF => {
+                    let meta_type = reader.u8()?;
+                    let data = reader.var_len_data()?;
+                    match meta_type {
+                        0x2F => {
+                            events.push((ticks, Event::EndOfTrack));
+                            break;
+                        },
+                        0x51 if data.len() == 3 => events.push((ticks, Event::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])))),
+                        0x58 if data.len() >= 2 => events.push((ticks, Event::TimeSignature(data[0], 1 << data[1].min(7)))),
+                        0x06 => events.push((ticks, Event::Marker(String::from_utf8_lossy(data).into_owned()))),
+                        _ => {}
+                    }
+                },
+                0xF0 | 0xF7 => {
+                    // sysex is not needed, so skipped
+                    reader.var_len_data()?;
+                },
+                _ => {
+                    let mut message = Vec::new();
+                    if status & 0x80 == 0 {
+                        // running status, so this byte is the first data byte
+                        message.push(status);
+                        status = running_status.ok_or(format!("Data byte without status at tick {}", ticks))?;
+                    } else {
+                        running_status = Some(status);
+                    }
+                    message.insert(0, status);
+                    let length = match status & 0xF0 {
+                        0xC0 | 0xD0 => 2,
+                        _ => 3
+                    };
+                    while message.len() < length {
+                        message.push(reader.u8()?);
+                    }
+                    let midi = MidiMessage::try_from(message.as_slice()).map_err(|e| format!("Invalid MIDI message at tick {}: {:?}", ticks, e))?;
+                    events.push((ticks, Event::Midi(midi.to_owned())));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// The length of a bar in ticks, from the first time signature (or 4/4 if there is none)
+    pub fn ticks_per_bar(&self) -> u32 {
+        let (numerator, denominator) = self.tracks.iter().flatten()
+            .filter_map(|(_, e)| match e {
+                Event::TimeSignature(n, d) => Some((*n, *d)),
+                _ => None
+            })
+            .next()
+            .unwrap_or((4, 4));
+        self.ticks_per_beat as u32 * 4 * numerator as u32 / denominator as u32
+    }
+
+    /// The tempo in microseconds per beat, from the first tempo event (or 120bpm if there is none)
+    pub fn tempo(&self) -> u32 {
+        self.tracks.iter().flatten()
+            .filter_map(|(_, e)| match e {
+                Event::Tempo(t) => Some(*t),
+                _ => None
+            })
+            .next()
+            .unwrap_or(500_000)
+    }
+
+    /// The text of every marker in any track
+    pub fn markers(&self) -> Vec<&str> {
+        self.tracks.iter().flatten()
+            .filter_map(|(_, e)| match e {
+                Event::Marker(text) => Some(text.as_str()),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// The time of the last event in any track
+    pub fn end_ticks(&self) -> u32 {
+        self.tracks.iter().filter_map(|t| t.last()).map(|(ticks, _)| *ticks).max().unwrap_or(0)
+    }
+
+    /// Divide notes (starting from `start`) into steps, with rests for any gaps, timed in ticks at the given resolution.
+    /// The last step lasts until `end`, or until its notes end if not set.
+    pub fn steps(&self, notes: Vec<FileNote>, start: u32, end: Option<u32>, ticks_per_beat: u32) -> Vec<FileStep> {
+        // convert from file ticks (relative to the start) to the new resolution
+        let convert = |ticks: u32| {
+            let relative = ticks.saturating_sub(start) as u64;
+            ((relative * ticks_per_beat as u64 + self.ticks_per_beat as u64 / 2) / self.ticks_per_beat as u64) as u32
+        };
+        let mut groups: Vec<(u32, Vec<FileNote>)> = Vec::new();
+        for note in notes {
+            let onset = convert(note.start);
+            match groups.last_mut() {
+                Some((last_onset, group)) if *last_onset == onset => group.push(note),
+                _ => groups.push((onset, vec![note]))
+            }
+        }
+        let last_end = match end {
+            Some(e) => convert(e),
+            None => groups.iter().flat_map(|(_, g)| g.iter()).map(|n| convert(n.end)).max().unwrap_or(0)
+        };
+        let mut steps = Vec::new();
+        let mut position = 0;
+        let onsets: Vec<u32> = groups.iter().map(|(o, _)| *o).collect();
+        for (i, (onset, group)) in groups.into_iter().enumerate() {
+            if onset > position {
+                steps.push(FileStep { notes: Vec::new(), ticks: onset - position });
+            }
+            let next = onsets.get(i + 1).cloned().unwrap_or(last_end.max(onset + 1));
+            let group_end = group.iter().map(|n| convert(n.end.min(end.unwrap_or(u32::MAX)))).max().unwrap_or(0);
+            let length = group_end.min(next).max(onset + 1) - onset;
+            steps.push(FileStep { notes: group, ticks: length });
+            position = onset + length;
+        }
+        if last_end > position {
+            steps.push(FileStep { notes: Vec::new(), ticks: last_end - position });
+        }
+        steps
+    }
+
+    /// The notes in the given track (or all tracks) and channel (or all channels), in order of when they start
+    pub fn notes(&self, track: Option<usize>, channel: Option<Channel>) -> Vec<FileNote> {
+        let mut notes = Vec::new();
+        for (t, events) in self.tracks.iter().enumerate() {
+            if track.map(|only| only != t).unwrap_or(false) {
+                continue;
+            }
+            let mut started: Vec<(Channel, FileNote)> = Vec::new();
+            for (ticks, event) in events {
+                let (c, n, on_velocity) = match event {
+                    Event::Midi(MidiMessage::NoteOn(c, n, v)) if u8::from(*v) > 0 => (*c, *n, Some(*v)),
+                    Event::Midi(MidiMessage::NoteOn(c, n, _)) | Event::Midi(MidiMessage::NoteOff(c, n, _)) => (*c, *n, None),
+                    _ => continue
+                };
+                if channel.map(|only| only != c).unwrap_or(false) {
+                    continue;
+                }
+                // a repeated note on also ends the previous note
+                if let Some(i) = started.iter().position(|(sc, s)| *sc == c && s.note == n) {
+                    let (_, mut ended) = started.remove(i);
+                    ended.end = *ticks;
+                    notes.push(ended);
+                }
+                if let Some(velocity) = on_velocity {
+                    started.push((c, FileNote { start: *ticks, end: *ticks, channel: c, note: n, velocity }));
+                }
+            }
+            // notes without a note off end at the end of the track
+            let track_end = events.last().map(|(t, _)| *t).unwrap_or(0);
+            notes.extend(started.into_iter().map(|(_, mut n)| {
+                n.end = track_end;
+                n
+            }));
+        }
+        notes.sort_by_key(|n| (n.start, u8::from(n.note)));
+        notes
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.position + length > self.bytes.len() {
+            return Err(format!("Unexpected end of data at byte {}", self.bytes.len()));
+        }
+        let taken = &self.bytes[self.position..(self.position + length)];
+        self.position += length;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn chunk(&mut self) -> Result<&'a [u8], String> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    fn var_len_data(&mut self) -> Result<&'a [u8], String> {
+        let length = self.var_len()? as usize;
+        self.take(length)
+    }
+
+    fn var_len(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!("Variable length value longer than 4 bytes at byte {}", self.position))
+    }
+}
+
+fn write_var_len(bytes: &mut Vec<u8>, mut value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.extend(groups.into_iter().rev());
+}
@@ -2,6 +2,7 @@ use std::cmp::min;
 use std::io::Write;
 
 use crate::arpeggiator::{ArpeggiatorMode, Pattern};
+use crate::chord::Chord;
 use crate::settings::Settings;
 use crate::midi::{self, MidiReceiver};
 
@@ -13,6 +14,7 @@ pub trait StatusSignal: MidiReceiver {
     fn update_settings(&mut self, settings: &Settings);
     fn update_count(&mut self, arpeggios: usize);
     fn reset_beat(&mut self);
+    fn update_chord(&mut self, chord: Option<Chord>);
     fn waiting_for_midi_connect(&mut self);
     fn waiting_for_midi_disconnect(&mut self);
     fn waiting_for_midi_clock(&mut self);
@@ -21,6 +23,7 @@ pub trait StatusSignal: MidiReceiver {
 pub struct TextStatus<W: Write> {
     count: Option<usize>,
     settings: Option<Settings>,
+    chord: Option<Chord>,
     waiting: Option<WaitFor>,
     writer: W
 }
@@ -37,6 +40,7 @@ impl<W: Write> TextStatus<W> {
         Self {
             count: None,
             settings: None,
+            chord: None,
             waiting: None,
             writer
         }
@@ -82,6 +86,15 @@ impl<W: Write> StatusSignal for TextStatus<W> {
     fn reset_beat(&mut self) {
         writeln!(self.writer, "**Reset beat**").unwrap();
     }
+
+    fn update_chord(&mut self, chord: Option<Chord>) {
+        if chord != self.chord {
+            if let Some(c) = &chord {
+                writeln!(self.writer, "Chord: {}", c).unwrap();
+            }
+            self.chord = chord;
+        }
+    }
     
     fn waiting_for_midi_connect(&mut self) {
         self.show_wait(WaitFor::Connect);
@@ -205,6 +218,10 @@ impl<const N: usize> StatusSignal for LedStatus<N> {
     fn reset_beat(&mut self) {
         self.tick = 0;
     }
+
+    fn update_chord(&mut self, _chord: Option<Chord>) {
+        // not enough LEDs to show a chord name
+    }
     
     fn waiting_for_midi_connect(&mut self) {
         self.show_wait(WaitFor::Connect);